@@ -0,0 +1,332 @@
+//! Reads and validates the bootloader configuration embedded in the kernel.
+//!
+//! Kernels place a `bootloader::config::Config` into an ELF note through the
+//! `bootloader_config!` macro. Its layout is shared with the bootloader through the `layout`
+//! module.
+
+use byteorder::{ByteOrder, LittleEndian};
+use xmas_elf::{
+    header,
+    program::{ProgramHeader, Type},
+    ElfFile,
+};
+
+use self::layout::{ByteField, U64Field, NOTE_HEADER_SIZE, NOTE_NAME, NOTE_TYPE};
+
+#[allow(dead_code)]
+#[path = "../../src/config/layout.rs"]
+mod layout;
+
+/// The virtual address at which the bootloader loads position independent kernels.
+const PIE_KERNEL_LOAD_BASE: u64 = 0xffff_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferMode {
+    VgaText80x25,
+    Vga320x200,
+}
+
+impl FramebufferMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => FramebufferMode::VgaText80x25,
+            _ => FramebufferMode::Vga320x200,
+        }
+    }
+}
+
+/// The configuration of the bootloader, as requested by the kernel.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub kernel_stack_size: u64,
+    pub physical_memory_offset: u64,
+    pub physical_memory_mmio_size: u64,
+    pub boot_info_address: u64,
+    pub map_physical_memory: bool,
+    pub framebuffer: FramebufferMode,
+    /// Zero means that the bootloader chooses the address.
+    pub kernel_stack_address: u64,
+    /// If set, the bootloader chooses random addresses and ignores the configured ones.
+    pub kaslr: bool,
+    pub initialize_tls: bool,
+    pub copy_kernel_segments: bool,
+    pub deny_writable_executable: bool,
+    pub global_kernel_mappings: bool,
+    /// Only used if the bootloader is built with the `recursive_page_table` feature.
+    pub recursive_index: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            kernel_stack_size: layout::KERNEL_STACK_SIZE.default,
+            physical_memory_offset: layout::PHYSICAL_MEMORY_OFFSET.default,
+            physical_memory_mmio_size: layout::PHYSICAL_MEMORY_MMIO_SIZE.default,
+            boot_info_address: layout::BOOT_INFO_ADDRESS.default,
+            map_physical_memory: layout::MAP_PHYSICAL_MEMORY.default != 0,
+            framebuffer: FramebufferMode::from_u8(layout::FRAMEBUFFER.default),
+            kernel_stack_address: layout::KERNEL_STACK_ADDRESS.default,
+            kaslr: layout::KASLR.default != 0,
+            initialize_tls: layout::INITIALIZE_TLS.default != 0,
+            copy_kernel_segments: layout::COPY_KERNEL_SEGMENTS.default != 0,
+            deny_writable_executable: layout::DENY_WRITABLE_EXECUTABLE.default != 0,
+            global_kernel_mappings: layout::GLOBAL_KERNEL_MAPPINGS.default != 0,
+            recursive_index: layout::RECURSIVE_INDEX.default,
+        }
+    }
+}
+
+/// Reads the configuration note of the kernel.
+///
+/// Returns the default configuration if the kernel doesn't contain a configuration note.
+pub fn read(elf_file: &ElfFile) -> Result<Config, String> {
+    let mut config = Config::default();
+    let desc = match find_note(elf_file)? {
+        Some(desc) => desc,
+        None => return Ok(config),
+    };
+
+    // fields that are missing in configurations of older kernels keep their default values
+    let read_u64 = |field: U64Field| match desc.get(field.offset..field.offset + 8) {
+        None => Ok(None),
+        Some(bytes) => match LittleEndian::read_u64(bytes) {
+            value if value <= field.max => Ok(Some(value)),
+            value => Err(format!("invalid value {} for {}", value, field.name)),
+        },
+    };
+    let read_byte = |field: ByteField| match desc.get(field.offset) {
+        None => Ok(None),
+        Some(&value) if value <= field.max => Ok(Some(value)),
+        Some(value) => Err(format!("invalid value {} for {}", value, field.name)),
+    };
+    let read_bool = |field: ByteField| read_byte(field).map(|value| value.map(|v| v != 0));
+
+    if let Some(value) = read_u64(layout::KERNEL_STACK_SIZE)? {
+        config.kernel_stack_size = value;
+    }
+    if let Some(value) = read_u64(layout::PHYSICAL_MEMORY_OFFSET)? {
+        config.physical_memory_offset = value;
+    }
+    if let Some(value) = read_u64(layout::PHYSICAL_MEMORY_MMIO_SIZE)? {
+        config.physical_memory_mmio_size = value;
+    }
+    if let Some(value) = read_u64(layout::BOOT_INFO_ADDRESS)? {
+        config.boot_info_address = value;
+    }
+    if let Some(value) = read_bool(layout::MAP_PHYSICAL_MEMORY)? {
+        config.map_physical_memory = value;
+    }
+    if let Some(value) = read_byte(layout::FRAMEBUFFER)? {
+        config.framebuffer = FramebufferMode::from_u8(value);
+    }
+    if let Some(value) = read_u64(layout::KERNEL_STACK_ADDRESS)? {
+        config.kernel_stack_address = value;
+    }
+    if let Some(value) = read_bool(layout::KASLR)? {
+        config.kaslr = value;
+    }
+    if let Some(value) = read_bool(layout::INITIALIZE_TLS)? {
+        config.initialize_tls = value;
+    }
+    if let Some(value) = read_bool(layout::COPY_KERNEL_SEGMENTS)? {
+        config.copy_kernel_segments = value;
+    }
+    if let Some(value) = read_bool(layout::DENY_WRITABLE_EXECUTABLE)? {
+        config.deny_writable_executable = value;
+    }
+    if let Some(value) = read_bool(layout::GLOBAL_KERNEL_MAPPINGS)? {
+        config.global_kernel_mappings = value;
+    }
+    if let Some(value) = read_u64(layout::RECURSIVE_INDEX)? {
+        config.recursive_index = value;
+    }
+
+    Ok(config)
+}
+
+/// Returns the descriptor of the configuration note.
+///
+/// Like the bootloader, this looks at the `PT_NOTE` program headers, so that the note is also
+/// found in kernels whose section headers were stripped.
+fn find_note<'a>(elf_file: &ElfFile<'a>) -> Result<Option<&'a [u8]>, String> {
+    for program_header in elf_file.program_iter() {
+        if program_header.get_type() != Ok(Type::Note) {
+            continue;
+        }
+        let start = program_header.offset() as usize;
+        let end = start + program_header.file_size() as usize;
+        let mut notes = elf_file
+            .input
+            .get(start..end)
+            .ok_or("note segment lies outside of the kernel executable")?;
+        // name and descriptor are padded to the alignment of the segment
+        let align = program_header.align().max(4) as usize;
+        let align_up = |offset: usize| (offset + align - 1) / align * align;
+
+        while notes.len() >= NOTE_HEADER_SIZE {
+            let name_size = LittleEndian::read_u32(&notes[0..4]) as usize;
+            let desc_start = align_up(NOTE_HEADER_SIZE + name_size);
+            let desc_end = desc_start + LittleEndian::read_u32(&notes[4..8]) as usize;
+            if notes.len() < desc_end {
+                return Err("note segment is truncated".into());
+            }
+            let name = &notes[NOTE_HEADER_SIZE..desc_start];
+            if LittleEndian::read_u32(&notes[8..12]) == NOTE_TYPE && name == NOTE_NAME {
+                return Ok(Some(&notes[desc_start..desc_end]));
+            }
+            notes = &notes[align_up(desc_end).min(notes.len())..];
+        }
+    }
+    Ok(None)
+}
+
+/// Checks that the configured values are valid and that the configured addresses don't
+/// overlap with the kernel segments.
+///
+/// The recursive page table entry is only checked if `recursive_page_table` is set, the
+/// physical memory offset if the kernel requests the mapping or if the bootloader is built with
+/// the `map_physical_memory` feature (`map_physical_memory_feature`). The size
+/// of the physical memory mapping is only known at boot time, so the bootloader checks it
+/// again before mapping anything. The same applies to the segments of position independent
+/// kernels with `kaslr`, which are not checked here.
+pub fn check(
+    config: &Config,
+    elf_file: &ElfFile,
+    recursive_page_table: bool,
+    map_physical_memory_feature: bool,
+) -> Result<(), String> {
+    fn is_canonical(addr: u64) -> bool {
+        let upper_bits = addr >> 47;
+        upper_bits == 0 || upper_bits == 0x1ffff
+    }
+
+    if config.kernel_stack_size == 0 {
+        return Err("kernel stack size must not be zero".into());
+    }
+    if config.recursive_index >= 512 {
+        return Err(format!("invalid recursive index {}", config.recursive_index));
+    }
+
+    let position_independent =
+        elf_file.header.pt2.type_().as_type() == header::Type::SharedObject;
+    let load_base = if position_independent {
+        PIE_KERNEL_LOAD_BASE
+    } else {
+        0
+    };
+    // With `kaslr`, the bootloader chooses the load base of position independent kernels at
+    // boot time and checks the segments against the configured addresses then.
+    let segments_known = !(position_independent && config.kaslr);
+    let segments: Vec<_> = elf_file
+        .program_iter()
+        .filter(|ph| segments_known && ph.get_type() == Ok(Type::Load))
+        .map(|ph| match ph {
+            ProgramHeader::Ph64(header) => (load_base + header.virtual_addr, header.mem_size),
+            ProgramHeader::Ph32(header) => {
+                (u64::from(header.virtual_addr), u64::from(header.mem_size))
+            }
+        })
+        .collect();
+    let check_overlap = |name: &str, start: u64, size: u64| {
+        let end = start.saturating_add(size);
+        for &(segment_start, segment_size) in &segments {
+            let segment_end = segment_start + segment_size;
+            if start < segment_end && segment_start < end {
+                return Err(format!(
+                    "{} at {:#x} overlaps with kernel segment at {:#x}..{:#x}",
+                    name, start, segment_start, segment_end
+                ));
+            }
+        }
+        Ok(())
+    };
+
+    // The bootloader maps the VGA buffer at a fixed address.
+    check_overlap("VGA buffer mapping", layout::VGA_BUFFER_ADDR, 4096)?;
+
+    let recursive_entry = if recursive_page_table {
+        // sign extend the start address of the level 4 entry
+        let start = config.recursive_index << 39;
+        let start = if start & (1 << 47) != 0 {
+            start | 0xffff_0000_0000_0000
+        } else {
+            start
+        };
+        check_overlap("recursive page table entry", start, 1 << 39)
+            .map_err(|err| format!("{} (use `recursive_index` to move the entry)", err))?;
+        if start >> 39 == layout::VGA_BUFFER_ADDR >> 39 {
+            return Err(format!(
+                "recursive page table entry {} collides with the VGA buffer mapping at {:#x} \
+                 (use `recursive_index` to move the entry)",
+                config.recursive_index,
+                layout::VGA_BUFFER_ADDR
+            ));
+        }
+        Some(start)
+    } else {
+        None
+    };
+    // Checks a configured region against the other mappings of the bootloader.
+    let check_mapping_overlap = |name: &str, start: u64, size: u64| {
+        let end = start.saturating_add(size);
+        if start < layout::VGA_BUFFER_ADDR + 4096 && layout::VGA_BUFFER_ADDR < end {
+            return Err(format!(
+                "{} at {:#x} overlaps with the VGA buffer mapping at {:#x}",
+                name,
+                start,
+                layout::VGA_BUFFER_ADDR
+            ));
+        }
+        if let Some(entry_start) = recursive_entry {
+            if start < entry_start.saturating_add(1 << 39) && entry_start < end {
+                return Err(format!(
+                    "{} at {:#x} overlaps with the recursive page table entry {}",
+                    name, start, config.recursive_index
+                ));
+            }
+        }
+        Ok(())
+    };
+
+    if config.map_physical_memory || map_physical_memory_feature {
+        let offset = config.physical_memory_offset;
+        if !is_canonical(offset) || offset % 0x200000 != 0 {
+            return Err(format!(
+                "physical memory offset {:#x} is not a canonical 2MiB aligned address",
+                offset
+            ));
+        }
+        check_overlap("physical memory offset", offset, 1)?;
+        check_mapping_overlap("physical memory offset", offset, 1)?;
+    }
+
+    let addr = config.boot_info_address;
+    if !is_canonical(addr) || addr % 4096 != 0 {
+        return Err(format!(
+            "boot info address {:#x} is not a canonical page aligned address",
+            addr
+        ));
+    }
+    check_overlap("boot info", addr, 4096)?;
+    check_mapping_overlap("boot info", addr, 4096)?;
+
+    let addr = config.kernel_stack_address;
+    if addr != 0 {
+        if !is_canonical(addr) || addr % 4096 != 0 {
+            return Err(format!(
+                "kernel stack address {:#x} is not a canonical page aligned address",
+                addr
+            ));
+        }
+        // the stacks of all processors, each with a guard page below it
+        let size = config
+            .kernel_stack_size
+            .checked_add(1)
+            .and_then(|pages| pages.checked_mul(4096 * layout::MAX_PROCESSORS))
+            .ok_or("kernel stack size is too large")?;
+        check_overlap("kernel stacks", addr - 4096, size)?;
+        check_mapping_overlap("kernel stacks", addr - 4096, size)?;
+    }
+
+    Ok(())
+}
@@ -1,12 +1,15 @@
+use crate::config::FramebufferMode;
 use args::Args;
 use byteorder::{ByteOrder, LittleEndian};
 use std::{
     fs::File,
-    io::{self, Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
     process,
 };
 
+mod config;
+
 const PROGRAM_NAME: &'static str = "builder";
 const PROGRAM_DESC: &'static str = "Builds the bootloader crate.";
 
@@ -51,7 +54,70 @@ fn main() {
                 .expect("Failed to write to stderr");
             process::exit(1);
         });
-    let kernel_info_block = create_kernel_info_block(kernel_size, None);
+
+    let mut kernel_bytes = Vec::new();
+    kernel_file
+        .read_to_end(&mut kernel_bytes)
+        .and_then(|_| kernel_file.seek(SeekFrom::Start(0)))
+        .expect("Failed to read kernel file");
+    let kernel_elf = xmas_elf::ElfFile::new(&kernel_bytes).unwrap_or_else(|err| {
+        writeln!(io::stderr(), "Failed to parse kernel ELF file: {}", err)
+            .expect("Failed to write to stderr");
+        process::exit(1);
+    });
+
+    // read the configuration of the kernel and apply the command line overrides
+
+    let physical_memory_mmio_size = number_arg(&args, "physical-memory-mmio-size");
+    let physical_memory_offset = number_arg(&args, "physical-memory-offset");
+    let boot_info_address = number_arg(&args, "boot-info-address");
+    // whether a feature of the bootloader crate is enabled, `recursive_page_table` being the
+    // only default feature
+    let feature_enabled = |name: &str| {
+        args.value_of("all-features").unwrap()
+            || (name == "recursive_page_table"
+                && !args.value_of::<bool>("no-default-features").unwrap())
+            || args
+                .optional_value_of::<String>("features")
+                .unwrap()
+                .map_or(false, |features| features.split_whitespace().any(|f| f == name))
+    };
+    // the kernel only gets a recursive page table entry with the `recursive_page_table` feature
+    let recursive_page_table = feature_enabled("recursive_page_table");
+    // the feature maps the physical memory regardless of the kernel configuration
+    let map_physical_memory_feature = feature_enabled("map_physical_memory");
+
+    let config = config::read(&kernel_elf).and_then(|mut config| {
+        if let Some(size) = physical_memory_mmio_size {
+            config.physical_memory_mmio_size = size;
+        }
+        if let Some(offset) = physical_memory_offset {
+            config.physical_memory_offset = offset;
+        }
+        if let Some(addr) = boot_info_address {
+            config.boot_info_address = addr;
+        }
+        config::check(
+            &config,
+            &kernel_elf,
+            recursive_page_table,
+            map_physical_memory_feature,
+        )?;
+        Ok(config)
+    });
+    let config = config.unwrap_or_else(|err| {
+        writeln!(io::stderr(), "Invalid bootloader configuration: {}", err)
+            .expect("Failed to write to stderr");
+        process::exit(1);
+    });
+
+    let kernel_info_block = create_kernel_info_block(
+        kernel_size,
+        None,
+        physical_memory_mmio_size,
+        physical_memory_offset,
+        boot_info_address,
+    );
 
     // build bootloader
 
@@ -68,7 +134,22 @@ fn main() {
     if args.value_of("all-features").unwrap() {
         build_args.push("--all-features".into());
     }
-    if let Some(features) = args.optional_value_of("features").unwrap() {
+    let mut features: String = args.optional_value_of("features").unwrap().unwrap_or_default();
+    // The video mode is set up by the assembly stages, so it must be selected at compile time.
+    let vga_320x200_feature = features.split_whitespace().any(|f| f == "vga_320x200");
+    match config.framebuffer {
+        FramebufferMode::Vga320x200 if !vga_320x200_feature => features.push_str(" vga_320x200"),
+        FramebufferMode::VgaText80x25 if vga_320x200_feature => {
+            writeln!(
+                io::stderr(),
+                "The `vga_320x200` feature conflicts with the framebuffer mode of the kernel"
+            )
+            .expect("Failed to write to stderr");
+            process::exit(1);
+        }
+        _ => {}
+    }
+    if !features.trim().is_empty() {
         build_args.push("--features".into());
         build_args.push(features);
     }
@@ -131,6 +212,33 @@ fn args() -> Args {
         Occur::Optional,
         None,
     );
+    args.option(
+        "",
+        "physical-memory-mmio-size",
+        "Size of the address space above the highest RAM address that is additionally \
+         mapped by the `map_physical_memory` feature, e.g. for memory mapped devices \
+         (decimal or 0x-prefixed hexadecimal)",
+        "BYTES",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "",
+        "physical-memory-offset",
+        "Virtual address at which the `map_physical_memory` feature maps the physical memory \
+         (must be 2MiB aligned)",
+        "ADDRESS",
+        Occur::Optional,
+        None,
+    );
+    args.option(
+        "",
+        "boot-info-address",
+        "Virtual address of the boot info structure (must be page aligned)",
+        "ADDRESS",
+        Occur::Optional,
+        None,
+    );
     args.flag("", "all-features", "Activate all available features");
     args.flag(
         "",
@@ -146,6 +254,26 @@ fn help_arg_present() -> bool {
         .is_some()
 }
 
+/// Parses the value of the given option as a decimal or `0x`-prefixed hexadecimal number.
+///
+/// Exits the process with an error message if the value is not a valid number.
+fn number_arg(args: &Args, name: &str) -> Option<u64> {
+    let value: String = args.optional_value_of(name).unwrap()?;
+    let result = if value.starts_with("0x") {
+        u64::from_str_radix(&value[2..].replace('_', ""), 16)
+    } else {
+        value.replace('_', "").parse()
+    };
+    match result {
+        Ok(number) => Some(number),
+        Err(err) => {
+            writeln!(io::stderr(), "Invalid value `{}` for --{}: {}", value, name, err)
+                .expect("Failed to write to stderr");
+            process::exit(1);
+        }
+    }
+}
+
 fn run_xbuild(args: &[String]) -> io::Result<process::ExitStatus> {
     let mut command = process::Command::new("cargo");
     command.arg("xbuild");
@@ -172,7 +300,13 @@ fn run_xbuild(args: &[String]) -> io::Result<process::ExitStatus> {
     Ok(exit_status)
 }
 
-fn create_kernel_info_block(kernel_size: u64, maybe_package_size: Option<u64>) -> KernelInfoBlock {
+fn create_kernel_info_block(
+    kernel_size: u64,
+    maybe_package_size: Option<u64>,
+    physical_memory_mmio_size: Option<u64>,
+    physical_memory_offset: Option<u64>,
+    boot_info_address: Option<u64>,
+) -> KernelInfoBlock {
     let kernel_size = if kernel_size <= u64::from(u32::max_value()) {
         kernel_size as u32
     } else {
@@ -192,6 +326,18 @@ fn create_kernel_info_block(kernel_size: u64, maybe_package_size: Option<u64>) -
     let mut kernel_info_block = [0u8; BLOCK_SIZE];
     LittleEndian::write_u32(&mut kernel_info_block[0..4], kernel_size);
     LittleEndian::write_u32(&mut kernel_info_block[8..12], package_size);
+    // the bootloader only uses the values whose bit is set in the override mask, and the
+    // values from the kernel configuration otherwise
+    let mut overrides = 0;
+    let overridable = [physical_memory_mmio_size, physical_memory_offset, boot_info_address];
+    for (i, value) in overridable.iter().enumerate() {
+        if let Some(value) = *value {
+            let offset = 16 + i * 8;
+            LittleEndian::write_u64(&mut kernel_info_block[offset..offset + 8], value);
+            overrides |= 1 << i;
+        }
+    }
+    LittleEndian::write_u64(&mut kernel_info_block[40..48], overrides);
 
     kernel_info_block
 }
@@ -0,0 +1,128 @@
+use bootloader::config::layout::{self, NOTE_HEADER_SIZE};
+use bootloader::config::{self, Config};
+use core::{cmp, mem, ptr};
+use usize_conversions::usize_from;
+use xmas_elf::program::Type;
+use xmas_elf::ElfFile;
+
+/// The bits in `_kib_overrides` that mark the values passed to the builder.
+const KIB_OVERRIDE_PHYSICAL_MEMORY_MMIO_SIZE: u64 = 1 << 0;
+const KIB_OVERRIDE_PHYSICAL_MEMORY_OFFSET: u64 = 1 << 1;
+const KIB_OVERRIDE_BOOT_INFO_ADDR: u64 = 1 << 2;
+
+/// Reads the configuration embedded in the kernel and applies the overrides from the kernel
+/// info block.
+///
+/// Kernels without a configuration note get [`Config::DEFAULT`].
+pub(crate) fn load(elf_file: &ElfFile) -> Config {
+    let mut config = Config::DEFAULT;
+
+    if let Some(desc) = find_note(elf_file) {
+        // Fields are only ever appended, so configurations of older kernels are a prefix of
+        // the current structure. Missing fields keep their default values, including a field
+        // that is cut off by the end of the descriptor.
+        let size = whole_fields_size(cmp::min(desc.len(), mem::size_of::<Config>()));
+        check_values(&desc[..size]);
+        unsafe {
+            ptr::copy_nonoverlapping(desc.as_ptr(), &mut config as *mut Config as *mut u8, size)
+        };
+    }
+
+    // Values passed to the builder take precedence. The builder sets a bit in
+    // `_kib_overrides` for every value that was passed, so that zero can be passed too.
+    let overrides = unsafe { super::_kib_overrides };
+    let apply_override = |field: &mut u64, value: u64, bit: u64| {
+        if overrides & bit != 0 {
+            *field = value;
+        }
+    };
+    unsafe {
+        apply_override(
+            &mut config.physical_memory_mmio_size,
+            super::_kib_physical_memory_mmio_size,
+            KIB_OVERRIDE_PHYSICAL_MEMORY_MMIO_SIZE,
+        );
+        apply_override(
+            &mut config.physical_memory_offset,
+            super::_kib_physical_memory_offset,
+            KIB_OVERRIDE_PHYSICAL_MEMORY_OFFSET,
+        );
+        apply_override(
+            &mut config.boot_info_address,
+            super::_kib_boot_info_addr,
+            KIB_OVERRIDE_BOOT_INFO_ADDR,
+        );
+    }
+
+    config
+}
+
+/// Returns the descriptor of the configuration note.
+///
+/// The note is found through the `PT_NOTE` program headers instead of the section headers,
+/// which may be stripped from the kernel executable.
+fn find_note<'a>(elf_file: &ElfFile<'a>) -> Option<&'a [u8]> {
+    fn read_u32(bytes: &[u8], offset: usize) -> usize {
+        bytes[offset..offset + 4]
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | usize::from(byte))
+    }
+
+    for program_header in elf_file.program_iter() {
+        if program_header.get_type() != Ok(Type::Note) {
+            continue;
+        }
+        let start = usize_from(program_header.offset());
+        let end = start + usize_from(program_header.file_size());
+        let mut notes = elf_file
+            .input
+            .get(start..end)
+            .expect("note segment lies outside of the kernel executable");
+        // name and descriptor are padded to the alignment of the segment
+        let align = cmp::max(usize_from(program_header.align()), 4);
+        let align_up = |offset: usize| (offset + align - 1) / align * align;
+
+        while notes.len() >= NOTE_HEADER_SIZE {
+            let name_size = read_u32(notes, 0);
+            let desc_start = align_up(NOTE_HEADER_SIZE + name_size);
+            let desc_end = desc_start + read_u32(notes, 4);
+            assert!(notes.len() >= desc_end, "note segment is truncated");
+            let name = &notes[NOTE_HEADER_SIZE..desc_start];
+            if read_u32(notes, 8) == config::NOTE_TYPE as usize && name == config::NOTE_NAME {
+                return Some(&notes[desc_start..desc_end]);
+            }
+            notes = &notes[cmp::min(align_up(desc_end), notes.len())..];
+        }
+    }
+    None
+}
+
+/// Returns the size of the longest prefix of `size` bytes that doesn't end inside a field.
+fn whole_fields_size(size: usize) -> usize {
+    let u64_field_ends = layout::U64_FIELDS.iter().map(|field| field.offset + 8);
+    let byte_field_ends = layout::BYTE_FIELDS.iter().map(|field| field.offset + 1);
+    u64_field_ends
+        .chain(byte_field_ends)
+        .filter(|&end| end <= size)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Makes sure that the raw bytes of the configuration are valid for the field types.
+fn check_values(bytes: &[u8]) {
+    for field in layout::BYTE_FIELDS.iter() {
+        if let Some(&value) = bytes.get(field.offset) {
+            assert!(value <= field.max, "invalid value {} for `{}`", value, field.name);
+        }
+    }
+    for field in layout::U64_FIELDS.iter() {
+        if let Some(value) = bytes.get(field.offset..field.offset + 8) {
+            let value = value
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | u64::from(byte));
+            assert!(value <= field.max, "invalid value {} for `{}`", value, field.name);
+        }
+    }
+}
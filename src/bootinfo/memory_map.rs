@@ -197,6 +197,17 @@ pub enum MemoryRegionType {
     BootInfo,
     /// Memory used for storing the supplied package
     Package,
+    /// Memory used by the bootloader that is no longer needed once the kernel runs.
+    ///
+    /// This includes the code, data, and stack of the bootloader and the page tables of
+    /// stage 3. The kernel can reuse it after it has finished with the boot information and
+    /// switched to its own page tables, which also removes the `BootInfo::handoff_region`.
+    BootloaderReclaimable,
+    /// Memory containing the kernel executable after its segments were copied to other frames.
+    ///
+    /// The kernel can reuse it once it no longer needs the executable, e.g. for reading its
+    /// debug information.
+    KernelImageReclaimable,
     /// Additional variant to ensure that we can add more variants in the future without
     /// breaking backwards compatibility.
     #[doc(hidden)]
@@ -30,7 +30,10 @@ pub struct BootInfo {
     /// used by the kernel.
     pub memory_map: MemoryMap,
     /// The virtual address of the recursively mapped level 4 page table.
-    #[cfg(feature = "recursive_page_table")]
+    ///
+    /// The recursive entry lies at the level 4 index that the kernel configured through
+    /// `Config::recursive_index` (511 by default). Zero if the level 4 page table is not
+    /// recursively mapped.
     pub recursive_page_table_addr: u64,
     /// The offset into the virtual address space where the physical memory is mapped.
     ///
@@ -40,25 +43,167 @@ pub struct BootInfo {
     /// frames that are also mapped at other virtual addresses can easily break memory safety and
     /// cause undefined behavior. Only frames reported as `USABLE` by the memory map in the `BootInfo`
     /// can be safely accessed.
-    #[cfg(feature = "map_physical_memory")]
+    ///
+    /// Zero if the physical memory is not mapped. It is mapped if the `map_physical_memory`
+    /// feature or the `map_physical_memory` configuration option is enabled.
     pub physical_memory_offset: u64,
+    /// The lowest virtual address of the kernel stack.
+    ///
+    /// The page below this address is left unmapped as a guard page, so that stack overflows
+    /// result in a page fault.
+    pub kernel_stack_bottom: u64,
+    /// The virtual address of the end of the kernel stack, which is the initial stack pointer.
+    ///
+    /// This is the stack of the bootstrap processor. The stacks of the additional processors
+    /// are returned by [`BootInfo::kernel_stack`].
+    pub kernel_stack_top: u64,
+    /// The number of processors that entered the kernel, including the bootstrap processor.
+    pub processor_count: u64,
+    /// The offset at which a position independent kernel was loaded.
+    ///
+    /// The bootloader applied the dynamic relocations of the kernel for this offset, so the
+    /// virtual address of every kernel symbol is its link address plus this offset. Always zero
+    /// for kernels that are not position independent.
+    pub kernel_load_base: u64,
+    /// The virtual address range of the trampoline that the bootloader used to jump to the
+    /// kernel.
+    ///
+    /// The page tables of the kernel don't map the bootloader itself, so this range (and the
+    /// recursive page table entry, if enabled) is the only part of the address space that the
+    /// kernel did not request. The processors enter the kernel with a GDT in this range, whose
+    /// memory is part of a `BootloaderReclaimable` region. Every processor must load a GDT of
+    /// the kernel and leave this range before the range is unmapped or that memory is reused.
+    pub handoff_region: VirtualRange,
+    kernel_stack_stride: u64,
+    tls_template: TlsTemplate,
+    writable_executable_regions: [VirtualRange; MAX_WRITABLE_EXECUTABLE_REGIONS],
+    writable_executable_region_count: u64,
     _non_exhaustive: u8, // `()` is not FFI safe
 }
 
 impl BootInfo {
     /// Create a new boot information structure. This function is only for internal purposes.
-    #[allow(unused_variables)]
     #[doc(hidden)]
     pub fn new(memory_map: MemoryMap, recursive_page_table_addr: u64, physical_memory_offset: u64) -> Self {
         BootInfo {
             memory_map,
-            #[cfg(feature = "recursive_page_table")]
             recursive_page_table_addr,
-            #[cfg(feature = "map_physical_memory")]
             physical_memory_offset,
+            kernel_stack_bottom: 0,
+            kernel_stack_top: 0,
+            processor_count: 1,
+            kernel_load_base: 0,
+            handoff_region: VirtualRange {
+                start_addr: 0,
+                end_addr: 0,
+            },
+            kernel_stack_stride: 0,
+            tls_template: TlsTemplate {
+                start_addr: 0,
+                file_size: 0,
+                mem_size: 0,
+                align: 0,
+            },
+            writable_executable_regions: [VirtualRange {
+                start_addr: 0,
+                end_addr: 0,
+            }; MAX_WRITABLE_EXECUTABLE_REGIONS],
+            writable_executable_region_count: 0,
             _non_exhaustive: 0,
         }
     }
+
+    /// Returns the bounds of the kernel stack of the `n`th started processor, where the
+    /// bootstrap processor is processor 0.
+    ///
+    /// Every stack has an unmapped guard page below its `start_addr`. The `end_addr` is the
+    /// initial stack pointer. Returns `None` if `n` is not below `processor_count`.
+    pub fn kernel_stack(&self, n: u64) -> Option<VirtualRange> {
+        if n >= self.processor_count {
+            return None;
+        }
+        Some(VirtualRange {
+            start_addr: self.kernel_stack_bottom + n * self.kernel_stack_stride,
+            end_addr: self.kernel_stack_top + n * self.kernel_stack_stride,
+        })
+    }
+
+    /// Sets the distance between two kernel stacks. This function is only for internal
+    /// purposes.
+    #[doc(hidden)]
+    pub fn set_kernel_stack_stride(&mut self, stride: u64) {
+        self.kernel_stack_stride = stride;
+    }
+
+    /// Returns information about the thread local storage (TLS) template of the kernel.
+    ///
+    /// Returns `None` if the kernel has no `PT_TLS` program header.
+    pub fn tls_template(&self) -> Option<TlsTemplate> {
+        if self.tls_template.mem_size == 0 {
+            None
+        } else {
+            Some(self.tls_template)
+        }
+    }
+
+    /// Sets the TLS template. This function is only for internal purposes.
+    #[doc(hidden)]
+    pub fn set_tls_template(&mut self, tls_template: TlsTemplate) {
+        self.tls_template = tls_template;
+    }
+
+    /// Returns the virtual address ranges that are mapped both writable and executable.
+    ///
+    /// This includes kernel segments with both permissions as well as mappings created by the
+    /// bootloader, e.g. the physical memory mapping. If there are more
+    /// ranges than fit into the boot information, the last range is extended to cover all
+    /// remaining ones.
+    pub fn writable_executable_regions(&self) -> &[VirtualRange] {
+        &self.writable_executable_regions[..self.writable_executable_region_count as usize]
+    }
+
+    /// Adds a writable and executable range. This function is only for internal purposes.
+    #[doc(hidden)]
+    pub fn add_writable_executable_region(&mut self, range: VirtualRange) {
+        let count = self.writable_executable_region_count as usize;
+        if count < MAX_WRITABLE_EXECUTABLE_REGIONS {
+            self.writable_executable_regions[count] = range;
+            self.writable_executable_region_count += 1;
+        } else {
+            let last = &mut self.writable_executable_regions[count - 1];
+            last.end_addr = last.end_addr.max(range.end_addr);
+        }
+    }
+}
+
+const MAX_WRITABLE_EXECUTABLE_REGIONS: usize = 16;
+
+/// A range of virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct VirtualRange {
+    /// The first address of the range.
+    pub start_addr: u64,
+    /// The address directly after the range.
+    pub end_addr: u64,
+}
+
+/// Information about the thread local storage (TLS) template of the kernel.
+///
+/// The template contains the initial values of all thread local variables. Each thread needs
+/// its own TLS block that is initialized from the template: the first `file_size` bytes are
+/// copied from `start_addr` and the remaining bytes up to `mem_size` are set to zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TlsTemplate {
+    /// The virtual start address of the template.
+    pub start_addr: u64,
+    /// The number of bytes of the template that are backed by data (`.tdata`).
+    pub file_size: u64,
+    /// The total size of the template, including the zero initialized part (`.tbss`).
+    pub mem_size: u64,
+    /// The required alignment of TLS blocks.
+    pub align: u64,
 }
 
 extern "C" {
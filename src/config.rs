@@ -0,0 +1,173 @@
+//! Allows kernels to configure the bootloader.
+//!
+//! The configuration is embedded into the kernel executable as an ELF note through the
+//! [`bootloader_config`] macro. The bootloader reads it when loading the kernel, so a single
+//! bootloader build can serve kernels with different needs.
+//!
+//! ```ignore
+//! use bootloader::config::Config;
+//!
+//! bootloader::bootloader_config!(Config {
+//!     kernel_stack_size: 1024,
+//!     map_physical_memory: true,
+//!     ..Config::DEFAULT
+//! });
+//! ```
+
+use core::mem;
+
+pub use self::layout::MAX_PROCESSORS;
+#[doc(hidden)]
+pub use self::layout::{NOTE_NAME, NOTE_TYPE};
+
+#[doc(hidden)]
+pub mod layout;
+
+/// The name of the ELF section that contains the configuration note.
+pub const SECTION_NAME: &str = ".note.bootloader-config";
+
+/// The configuration of the bootloader.
+///
+/// Fields are only ever appended to this structure, so that bootloaders can read
+/// configurations of kernels built against older versions of this crate. The byte offset of
+/// every field is recorded in the `layout` module, which the `builder` shares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Config {
+    /// The size of the kernel stack of each processor in 4KiB pages.
+    pub kernel_stack_size: u64,
+    /// The virtual address at which the physical memory is mapped if `map_physical_memory`
+    /// is set. Must be 2MiB aligned.
+    pub physical_memory_offset: u64,
+    /// The size of the address space above the highest RAM address that is additionally
+    /// mapped if `map_physical_memory` is set, e.g. to access memory mapped devices.
+    pub physical_memory_mmio_size: u64,
+    /// The virtual address of the page that contains the boot information. Must be page
+    /// aligned.
+    pub boot_info_address: u64,
+    /// Whether to map the complete physical memory at `physical_memory_offset`.
+    pub map_physical_memory: bool,
+    /// The video mode the bootloader sets up before jumping to the kernel.
+    pub framebuffer: FramebufferMode,
+    /// The virtual start address of the kernel stack of the bootstrap processor. Must be page
+    /// aligned.
+    ///
+    /// The page below this address is left unmapped as a guard page. The stacks of the
+    /// additional processors follow above, each with its own guard page, so the area spans
+    /// [`MAX_PROCESSORS`] stacks. If zero, the bootloader places the stacks into an
+    /// unused part of the address space.
+    pub kernel_stack_address: u64,
+    /// Whether to randomize the virtual addresses of the kernel and the regions the
+    /// bootloader maps for it.
+    ///
+    /// If set, position independent kernels are loaded at a random address, and the boot
+    /// information, the kernel stack and the physical memory mapping are placed at random
+    /// addresses. The configured addresses for these regions are ignored in this case.
+    pub kaslr: bool,
+    /// Whether to set up a thread local storage (TLS) block for every processor.
+    ///
+    /// If set and the kernel has a `PT_TLS` program header, the bootloader initializes a TLS
+    /// block from the template for each processor and points the `FS` base register to it,
+    /// so that `#[thread_local]` statics can be used right away.
+    pub initialize_tls: bool,
+    /// Whether to copy the kernel segments to newly allocated memory.
+    ///
+    /// By default, the segments are mapped directly from the kernel executable, which requires
+    /// that their virtual addresses and file offsets are equal modulo the page size. If set,
+    /// every segment gets its own frames and the memory of the kernel executable is marked
+    /// as `KernelImageReclaimable` afterwards.
+    pub copy_kernel_segments: bool,
+    /// Whether to refuse mappings that are both writable and executable.
+    ///
+    /// If set, kernels with writable and executable segments are rejected and the data
+    /// regions mapped by the bootloader (e.g. the boot information, the kernel stack, and the
+    /// physical memory mapping) are not executable. Independent of this option, all writable
+    /// and executable mappings are reported in `BootInfo::writable_executable_regions`.
+    pub deny_writable_executable: bool,
+    /// Whether to map the higher half of the address space with global pages.
+    ///
+    /// Global pages stay in the TLB when `CR3` is reloaded, which is useful for kernels that
+    /// map themselves into the higher half of every address space. This applies to the
+    /// kernel segments and all other higher half regions that the bootloader maps.
+    pub global_kernel_mappings: bool,
+    /// The level 4 index of the recursive entry in the page tables of the kernel. Must be
+    /// below 512.
+    ///
+    /// Only used if the bootloader is built with the `recursive_page_table` feature. The entry
+    /// occupies 512GiB of the address space, which must not contain any kernel segments,
+    /// configured regions, or the VGA buffer mapping at [`layout::VGA_BUFFER_ADDR`] (level 4
+    /// index 510). The address of the recursively mapped level 4 table is reported in
+    /// `BootInfo::recursive_page_table_addr`.
+    // not a `u16`, so that the field doesn't lie in the padding of older configurations
+    pub recursive_index: u64,
+}
+
+impl Config {
+    /// The configuration used for kernels that don't specify one.
+    pub const DEFAULT: Config = Config {
+        kernel_stack_size: layout::KERNEL_STACK_SIZE.default,
+        physical_memory_offset: layout::PHYSICAL_MEMORY_OFFSET.default,
+        physical_memory_mmio_size: layout::PHYSICAL_MEMORY_MMIO_SIZE.default,
+        boot_info_address: layout::BOOT_INFO_ADDRESS.default,
+        map_physical_memory: layout::MAP_PHYSICAL_MEMORY.default != 0,
+        // `layout::FRAMEBUFFER.default` is the discriminant of this mode
+        framebuffer: FramebufferMode::VgaText80x25,
+        kernel_stack_address: layout::KERNEL_STACK_ADDRESS.default,
+        kaslr: layout::KASLR.default != 0,
+        initialize_tls: layout::INITIALIZE_TLS.default != 0,
+        copy_kernel_segments: layout::COPY_KERNEL_SEGMENTS.default != 0,
+        deny_writable_executable: layout::DENY_WRITABLE_EXECUTABLE.default != 0,
+        global_kernel_mappings: layout::GLOBAL_KERNEL_MAPPINGS.default != 0,
+        recursive_index: layout::RECURSIVE_INDEX.default,
+    };
+}
+
+/// The video modes supported by the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FramebufferMode {
+    /// VGA text mode with 80x25 characters. The text buffer lives at address `0xb8000`.
+    VgaText80x25 = 0,
+    /// VGA mode 0x13, a graphics mode with 320x200 pixels and 256 colors. The framebuffer
+    /// is linear and lives at address `0xa0000`.
+    Vga320x200 = 1,
+}
+
+/// An ELF note that contains a [`Config`].
+#[doc(hidden)]
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct ConfigNote {
+    pub name_size: u32,
+    pub desc_size: u32,
+    pub note_type: u32,
+    pub name: [u8; 12],
+    pub config: Config,
+}
+
+#[doc(hidden)]
+impl ConfigNote {
+    pub const fn new(config: Config) -> Self {
+        ConfigNote {
+            name_size: 11,
+            desc_size: mem::size_of::<Config>() as u32,
+            note_type: NOTE_TYPE,
+            name: NOTE_NAME,
+            config,
+        }
+    }
+}
+
+/// Embeds the passed bootloader [`Config`] into the kernel executable.
+///
+/// The configuration is placed in a `.note.bootloader-config` section, which the linker keeps
+/// even when unused sections are garbage collected.
+#[macro_export]
+macro_rules! bootloader_config {
+    ($config:expr) => {
+        #[link_section = ".note.bootloader-config"]
+        #[used]
+        static __BOOTLOADER_CONFIG: $crate::config::ConfigNote =
+            $crate::config::ConfigNote::new($config);
+    };
+}
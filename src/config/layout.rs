@@ -0,0 +1,128 @@
+//! The byte layout of [`Config`](super::Config) in the descriptor of the configuration note.
+//!
+//! This file is also compiled into the `builder`, which reads the configuration of kernels
+//! without depending on this crate, so it must not refer to any other items of the crate.
+//! Fields are only ever appended, matching the order of the fields of `Config`.
+
+/// The owner name of the configuration note (zero padded to a multiple of 4 bytes).
+pub const NOTE_NAME: [u8; 12] = *b"bootloader\0\0";
+
+/// The type of the configuration note.
+pub const NOTE_TYPE: u32 = 1;
+
+/// The size of the note header (name size, descriptor size, type).
+pub const NOTE_HEADER_SIZE: usize = 12;
+
+/// The maximum number of processors that are started, including the bootstrap processor.
+///
+/// Every processor gets its own kernel stack, so the stack area at `kernel_stack_address`
+/// spans this many stacks.
+pub const MAX_PROCESSORS: u64 = 128;
+
+/// The virtual address at which the bootloader maps the VGA text buffer for the kernel.
+///
+/// The configured addresses and the recursive entry must not collide with this page.
+pub const VGA_BUFFER_ADDR: u64 = 0xffff_ff00_f000_0000;
+
+/// A field of the configuration that is stored as a little endian `u64`.
+#[derive(Debug, Clone, Copy)]
+pub struct U64Field {
+    /// The name of the field in `Config`.
+    pub name: &'static str,
+    /// The byte offset of the field in the note descriptor.
+    pub offset: usize,
+    /// The value of the field if the kernel doesn't specify it.
+    pub default: u64,
+    /// The largest valid value.
+    pub max: u64,
+}
+
+/// A field of the configuration that is stored as a single byte, e.g. a `bool`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteField {
+    /// The name of the field in `Config`.
+    pub name: &'static str,
+    /// The byte offset of the field in the note descriptor.
+    pub offset: usize,
+    /// The value of the field if the kernel doesn't specify it.
+    pub default: u8,
+    /// The largest valid value.
+    pub max: u8,
+}
+
+const fn u64_field(name: &'static str, offset: usize, default: u64) -> U64Field {
+    U64Field {
+        name,
+        offset,
+        default,
+        max: u64::max_value(),
+    }
+}
+
+const fn bool_field(name: &'static str, offset: usize) -> ByteField {
+    ByteField {
+        name,
+        offset,
+        default: 0,
+        max: 1,
+    }
+}
+
+/// The size of the kernel stack in 4KiB pages.
+pub const KERNEL_STACK_SIZE: U64Field = u64_field("kernel_stack_size", 0, 512);
+/// The virtual address of the physical memory mapping.
+pub const PHYSICAL_MEMORY_OFFSET: U64Field =
+    u64_field("physical_memory_offset", 8, 0o_177777_770_000_000_000_0000);
+/// The size of the address space that is mapped above the highest RAM address.
+pub const PHYSICAL_MEMORY_MMIO_SIZE: U64Field = u64_field("physical_memory_mmio_size", 16, 0);
+/// The virtual address of the boot information.
+pub const BOOT_INFO_ADDRESS: U64Field = u64_field("boot_info_address", 24, 0xb0071f0000);
+/// Whether to map the complete physical memory.
+pub const MAP_PHYSICAL_MEMORY: ByteField = bool_field("map_physical_memory", 32);
+/// The video mode, stored as the discriminant of `FramebufferMode`.
+pub const FRAMEBUFFER: ByteField = ByteField {
+    name: "framebuffer",
+    offset: 33,
+    default: 0,
+    max: 1,
+};
+/// The virtual start address of the kernel stack, or zero.
+pub const KERNEL_STACK_ADDRESS: U64Field = u64_field("kernel_stack_address", 40, 0);
+/// Whether to randomize the address space layout.
+pub const KASLR: ByteField = bool_field("kaslr", 48);
+/// Whether to set up a TLS block for every processor.
+pub const INITIALIZE_TLS: ByteField = bool_field("initialize_tls", 49);
+/// Whether to copy the kernel segments to newly allocated memory.
+pub const COPY_KERNEL_SEGMENTS: ByteField = bool_field("copy_kernel_segments", 50);
+/// Whether to refuse mappings that are both writable and executable.
+pub const DENY_WRITABLE_EXECUTABLE: ByteField = bool_field("deny_writable_executable", 51);
+/// Whether to map the higher half with global pages.
+pub const GLOBAL_KERNEL_MAPPINGS: ByteField = bool_field("global_kernel_mappings", 52);
+/// The level 4 index of the recursive page table entry.
+pub const RECURSIVE_INDEX: U64Field = U64Field {
+    name: "recursive_index",
+    offset: 56,
+    default: 511,
+    max: 511,
+};
+
+/// All `u64` fields of the configuration.
+pub const U64_FIELDS: [U64Field; 6] = [
+    KERNEL_STACK_SIZE,
+    PHYSICAL_MEMORY_OFFSET,
+    PHYSICAL_MEMORY_MMIO_SIZE,
+    BOOT_INFO_ADDRESS,
+    KERNEL_STACK_ADDRESS,
+    RECURSIVE_INDEX,
+];
+
+/// All single byte fields of the configuration.
+pub const BYTE_FIELDS: [ByteField; 7] = [
+    MAP_PHYSICAL_MEMORY,
+    FRAMEBUFFER,
+    KASLR,
+    INITIALIZE_TLS,
+    COPY_KERNEL_SEGMENTS,
+    DENY_WRITABLE_EXECUTABLE,
+    GLOBAL_KERNEL_MAPPINGS,
+];
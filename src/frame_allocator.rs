@@ -1,6 +1,8 @@
 use super::{frame_range, phys_frame_range};
 use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
-use x86_64::structures::paging::{PhysFrame, PhysFrameRange, self, Size4KiB};
+use x86_64::{align_up, PhysAddr};
+use x86_64::structures::paging::{self, PageSize, PhysFrame, PhysFrameRange};
+use x86_64::structures::paging::{Size1GiB, Size2MiB, Size4KiB};
 
 pub(crate) struct FrameAllocator<'a> {
     pub memory_map: &'a mut MemoryMap,
@@ -63,6 +65,56 @@ impl<'a> FrameAllocator<'a> {
         }
     }
 
+    /// Allocates `count` physically contiguous 4KiB frames and marks them as `region_type`.
+    ///
+    /// The start address of the returned range is aligned to `align` bytes, which must be a
+    /// power of two and at least 4KiB.
+    pub(crate) fn allocate_contiguous(
+        &mut self,
+        count: u64,
+        align: u64,
+        region_type: MemoryRegionType,
+    ) -> Option<PhysFrameRange> {
+        assert!(count > 0, "cannot allocate an empty frame range");
+        assert!(
+            align.is_power_of_two() && align >= Size4KiB::SIZE,
+            "invalid frame alignment {:#x}",
+            align
+        );
+
+        let size = count * Size4KiB::SIZE;
+        let start_addr = self
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .filter_map(|r| {
+                let start = align_up(r.range.start_addr(), align);
+                if start < r.range.end_addr() && r.range.end_addr() - start >= size {
+                    Some(start)
+                } else {
+                    None
+                }
+            })
+            .next()?;
+
+        let start_frame = PhysFrame::containing_address(PhysAddr::new(start_addr));
+        let range = PhysFrame::range(start_frame, start_frame + count);
+        self.mark_allocated_region(MemoryRegion {
+            range: frame_range(range),
+            region_type,
+        });
+        Some(range)
+    }
+
+    /// Allocates a single frame of size `S` that is aligned to its size.
+    pub(crate) fn allocate_large_frame<S: PageSize>(
+        &mut self,
+        region_type: MemoryRegionType,
+    ) -> Option<PhysFrame<S>> {
+        let range = self.allocate_contiguous(S::SIZE / Size4KiB::SIZE, S::SIZE, region_type)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+
     /// Marks the passed region in the memory map.
     ///
     /// Panics if a non-usable region (e.g. a reserved region) overlaps with the passed region.
@@ -88,7 +140,7 @@ impl<'a> FrameAllocator<'a> {
                     // ----rrrrrrrrrrr----
                     // ----RRRR-----------
                     r.range.start_frame_number = region.range.end_frame_number;
-                    self.memory_map.add_region(region);
+                    add_merged_region(self.memory_map, region);
                 } else {
                     // Case: (r = `r`, R = `region`)
                     // ----rrrrrrrrrrr----
@@ -104,30 +156,107 @@ impl<'a> FrameAllocator<'a> {
                     behind_r.range.start_frame_number = region.range.end_frame_number;
                     r.range.end_frame_number = region.range.start_frame_number;
                     self.memory_map.add_region(behind_r);
-                    self.memory_map.add_region(region);
+                    add_merged_region(self.memory_map, region);
                 } else {
                     // Case: (r = `r`, R = `region`)
                     // ----rrrrrrrrrrr----
                     // -----------RRRR---- or
                     // -------------RRRR--
                     r.range.end_frame_number = region.range.start_frame_number;
-                    self.memory_map.add_region(region);
+                    add_merged_region(self.memory_map, region);
                 }
             } else {
                 // Case: (r = `r`, R = `region`)
                 // ----rrrrrrrrrrr----
                 // --RRRR-------------
                 r.range.start_frame_number = region.range.end_frame_number;
-                self.memory_map.add_region(region);
+                add_merged_region(self.memory_map, region);
             }
             return;
         }
         panic!("region {:x?} is not a usable memory region", region);
     }
+
+    /// Changes the type of the frames in `range` from `old_type` to `new_type`.
+    ///
+    /// Panics if the range is not part of a single region of type `old_type`.
+    pub(crate) fn change_region_type(
+        &mut self,
+        range: PhysFrameRange,
+        old_type: MemoryRegionType,
+        new_type: MemoryRegionType,
+    ) {
+        let range = frame_range(range);
+        let changed = MemoryRegion {
+            range,
+            region_type: new_type,
+        };
+
+        let r = self
+            .memory_map
+            .iter_mut()
+            .find(|r| {
+                r.region_type == old_type
+                    && r.range.start_frame_number <= range.start_frame_number
+                    && range.end_frame_number <= r.range.end_frame_number
+            })
+            .unwrap_or_else(|| panic!("{:x?} is not part of a {:?} region", range, old_type));
+        let mut behind_r = r.clone();
+        behind_r.range.start_frame_number = range.end_frame_number;
+        let add_changed = if r.range.start_frame_number == range.start_frame_number {
+            *r = changed;
+            false
+        } else {
+            r.range.end_frame_number = range.start_frame_number;
+            true
+        };
+
+        if add_changed {
+            self.memory_map.add_region(changed);
+        }
+        if !behind_r.range.is_empty() {
+            self.memory_map.add_region(behind_r);
+        }
+    }
+}
+
+/// Adds `region` to the memory map, or extends an adjacent region of the same type instead.
+///
+/// This keeps repeated allocations (e.g. of the frames of the kernel stacks) from exceeding the
+/// maximum number of regions in the memory map.
+fn add_merged_region(memory_map: &mut MemoryMap, region: MemoryRegion) {
+    for r in memory_map.iter_mut() {
+        if r.region_type != region.region_type || r.range.is_empty() {
+            continue;
+        }
+        if r.range.end_frame_number == region.range.start_frame_number {
+            r.range.end_frame_number = region.range.end_frame_number;
+            return;
+        }
+        if r.range.start_frame_number == region.range.end_frame_number {
+            r.range.start_frame_number = region.range.start_frame_number;
+            return;
+        }
+    }
+    memory_map.add_region(region);
 }
 
 impl<'a> paging::FrameAllocator<Size4KiB> for FrameAllocator<'a> {
     fn alloc(&mut self) -> Option<PhysFrame<Size4KiB>> {
         self.allocate_frame(MemoryRegionType::PageTable)
     }
-}
\ No newline at end of file
+}
+
+// Huge frames are only requested for kernel memory, never for page tables.
+
+impl<'a> paging::FrameAllocator<Size2MiB> for FrameAllocator<'a> {
+    fn alloc(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_large_frame(MemoryRegionType::Kernel)
+    }
+}
+
+impl<'a> paging::FrameAllocator<Size1GiB> for FrameAllocator<'a> {
+    fn alloc(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_large_frame(MemoryRegionType::Kernel)
+    }
+}
@@ -0,0 +1,122 @@
+//! The final jump from the bootloader to the kernel.
+//!
+//! The jump goes through the trampoline in `handoff.s`, which is mapped to the higher half in
+//! addition to the identity mapping of the bootloader. This way, all processors can switch to
+//! the page tables of the kernel, which don't map the bootloader, right before they enter the
+//! kernel.
+
+use crate::frame_allocator::FrameAllocator;
+use crate::offset_page_table::OffsetPageTable;
+use crate::page_table::map_window;
+use bootloader::bootinfo::VirtualRange;
+use x86_64::structures::paging::{MapToError, Page, PageSize, PageTableFlags, PhysFrame};
+use x86_64::structures::paging::Size4KiB;
+use x86_64::{PhysAddr, VirtAddr};
+
+// Symbols defined in `linker.ld` and `handoff.s`
+extern "C" {
+    static __handoff_start: usize;
+    static __handoff_data_start: usize;
+    static __handoff_end: usize;
+    fn handoff();
+}
+
+type Handoff = unsafe extern "C" fn(u64, u64, u64, u64, u64) -> !;
+
+/// The virtual address of the higher half mapping of `handoff`.
+static mut HANDOFF_ADDR: u64 = 0;
+
+fn symbol_addr(symbol: &usize) -> u64 {
+    symbol as *const _ as u64
+}
+
+/// The size of the trampoline code and data in bytes.
+pub(crate) fn size() -> u64 {
+    unsafe { symbol_addr(&__handoff_end) - symbol_addr(&__handoff_start) }
+}
+
+/// Maps the trampoline to the pages starting at `window` and returns the mapped range.
+///
+/// The code is mapped read-only and the data non-executable. Both are part of the identity
+/// mapped bootloader, so their physical and lower half virtual addresses are the same.
+pub(crate) fn map(
+    window: Page,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<VirtualRange, MapToError> {
+    let (start, data_start, end) = unsafe {
+        (
+            symbol_addr(&__handoff_start),
+            symbol_addr(&__handoff_data_start),
+            symbol_addr(&__handoff_end),
+        )
+    };
+    let start_frame = PhysFrame::containing_address(PhysAddr::new(start));
+    let code_pages = (data_start - start) / Size4KiB::SIZE;
+    let data_pages = (end - data_start) / Size4KiB::SIZE;
+
+    map_window(
+        start_frame,
+        code_pages,
+        window,
+        PageTableFlags::PRESENT,
+        page_table,
+        frame_allocator,
+    )?;
+    map_window(
+        start_frame + code_pages,
+        data_pages,
+        window + code_pages,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        page_table,
+        frame_allocator,
+    )?;
+
+    let window_start = window.start_address().as_u64();
+    unsafe { HANDOFF_ADDR = window_start + (handoff as usize as u64 - start) };
+    Ok(VirtualRange {
+        start_addr: window_start,
+        end_addr: window_start + (end - start),
+    })
+}
+
+/// Switches to the level 4 page table in `page_table` and jumps to the kernel on the bootstrap
+/// processor.
+///
+/// The page table is passed on to the `other_processors` that were started, which wait for it
+/// in [`enter_kernel_on_other_processor`]. The bootstrap processor switches last, so that the
+/// page tables of the bootloader are no longer in use when the kernel starts. If some of them
+/// don't switch within a few seconds, it prints a message and halts instead.
+pub(crate) unsafe fn enter_kernel(
+    boot_info: VirtAddr,
+    entry_point: VirtAddr,
+    stack_pointer: VirtAddr,
+    page_table: PhysFrame,
+    other_processors: u64,
+) -> ! {
+    let handoff: Handoff = core::mem::transmute(HANDOFF_ADDR);
+    handoff(
+        boot_info.as_u64(),
+        entry_point.as_u64(),
+        stack_pointer.as_u64(),
+        page_table.start_address().as_u64(),
+        other_processors,
+    )
+}
+
+/// Jumps to the kernel on an additional processor after the bootstrap processor has passed the
+/// page table of the kernel to [`enter_kernel`].
+pub(crate) unsafe fn enter_kernel_on_other_processor(
+    boot_info: VirtAddr,
+    entry_point: VirtAddr,
+    stack_pointer: VirtAddr,
+) -> ! {
+    let handoff: Handoff = core::mem::transmute(HANDOFF_ADDR);
+    handoff(
+        boot_info.as_u64(),
+        entry_point.as_u64(),
+        stack_pointer.as_u64(),
+        0,
+        0,
+    )
+}
@@ -0,0 +1,106 @@
+.intel_syntax noprefix
+.code64
+
+# This trampoline performs the final jump to the kernel. It is additionally
+# mapped to the higher half, so that it stays accessible when it switches to
+# the page tables of the kernel, which don't map the bootloader. It must not
+# reference anything outside of the handoff sections.
+#
+# Arguments (System V calling convention):
+#   rdi: address of the boot info, passed on to the kernel
+#   rsi: entry point of the kernel
+#   rdx: stack pointer for the kernel
+#   rcx: physical address of the level 4 page table of the kernel, or 0 on the
+#        additional processors
+#   r8:  number of additional processors that use the trampoline
+#
+# If the additional processors don't switch to the page tables of the kernel
+# in time, the bootstrap processor prints a message to the VGA text buffer
+# (identity mapped by the bootloader) and halts instead of entering the kernel.
+
+# The number of iterations the bootstrap processor waits for the additional
+# processors, which takes at least several seconds.
+.set HANDOFF_TIMEOUT, 100000000
+
+.section .handoff, "ax"
+.global handoff
+
+handoff:
+    mov rsp, rdx
+
+    # load a copy of the GDT that stays mapped. It lives in bootloader memory,
+    # so the kernel must load its own GDT before it reuses that memory.
+    lea rax, [rip + handoff_gdt]
+    mov [rip + handoff_gdt_pointer + 2], rax
+    lgdt [rip + handoff_gdt_pointer]
+
+    test rcx, rcx
+    jnz handoff_publish_page_table
+
+    # additional processor: wait until the page table of the kernel is ready
+handoff_wait_for_page_table:
+    pause
+    mov rax, [rip + handoff_page_table]
+    test rax, rax
+    jz handoff_wait_for_page_table
+    mov cr3, rax
+    # report that we no longer use the page tables of the bootloader
+    lock inc qword ptr [rip + handoff_count]
+    jmp handoff_jump
+
+handoff_publish_page_table:
+    mov [rip + handoff_page_table], rcx
+    mov r9, HANDOFF_TIMEOUT
+handoff_wait_for_other_processors:
+    cmp [rip + handoff_count], r8
+    jae handoff_switch_page_table
+    pause
+    dec r9
+    jnz handoff_wait_for_other_processors
+
+    # some additional processor still uses the page tables of the bootloader
+    lea rax, [rip + handoff_timeout_str]
+    mov edx, 0xb8000
+handoff_print_timeout:
+    mov r9b, [rax]
+    test r9b, r9b
+    jz handoff_halt
+    mov [rdx], r9b
+    mov byte ptr [rdx + 1], 0x4f
+    inc rax
+    add rdx, 2
+    jmp handoff_print_timeout
+handoff_halt:
+    cli
+    hlt
+    jmp handoff_halt
+
+handoff_switch_page_table:
+    mov cr3, rcx
+
+handoff_jump:
+    jmp rsi
+
+.section .handoff_data, "aw"
+
+handoff_page_table:
+    .quad 0
+
+handoff_count:
+    .quad 0
+
+handoff_timeout_str:
+    .asciz "Additional processors did not switch to the kernel page tables"
+
+.align 8
+handoff_gdt:
+    .quad 0x0000000000000000          # Null Descriptor - should be present.
+    .quad 0x00209A0000000000          # 64-bit code descriptor (exec/read).
+    .quad 0x0000920000000000          # 64-bit data descriptor (read/write).
+
+.align 4
+    .word 0                           # Padding to make the "address of the GDT" field aligned on a 4-byte boundary
+
+handoff_gdt_pointer:
+    .word handoff_gdt_pointer - handoff_gdt - 1    # 16-bit Size (Limit) of GDT.
+    .quad 0                                        # 64-bit Base Address of GDT, set by `handoff`.
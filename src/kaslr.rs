@@ -0,0 +1,186 @@
+//! Kernel address space layout randomization.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step, _rdtsc};
+use x86_64::structures::paging::{Page, PageTable};
+use x86_64::ux::u9;
+
+/// The size of the address space covered by a single level 4 entry (512GiB).
+const P4_ENTRY_SIZE: u64 = 1 << 39;
+
+/// A random number generator based on the hardware random number generator of the CPU.
+///
+/// Falls back to the jitter of the time stamp counter if neither RDSEED nor RDRAND is
+/// supported.
+pub(crate) struct Rng {
+    source: Source,
+    state: u64,
+}
+
+enum Source {
+    RdSeed,
+    RdRand,
+    TscJitter,
+}
+
+impl Rng {
+    pub(crate) fn new() -> Self {
+        let leaf_1 = unsafe { __cpuid(1) };
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        let source = if max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0 {
+            Source::RdSeed
+        } else if leaf_1.ecx & (1 << 30) != 0 {
+            Source::RdRand
+        } else {
+            Source::TscJitter
+        };
+        Rng {
+            source,
+            state: tsc_jitter(),
+        }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        let hardware_value = match self.source {
+            Source::RdSeed => unsafe { rdseed() },
+            Source::RdRand => unsafe { rdrand() },
+            Source::TscJitter => None,
+        };
+        // Mix in the state, so that a failing hardware generator doesn't return constants.
+        self.state ^= hardware_value.unwrap_or_else(tsc_jitter);
+        splitmix64(&mut self.state)
+    }
+
+    /// Returns a random number in `0..bound`.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    // RDSEED fails if the entropy source is temporarily exhausted
+    for _ in 0..64 {
+        if _rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..10 {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Gathers entropy from the timing variations of a short busy loop.
+fn tsc_jitter() -> u64 {
+    let mut value = 0u64;
+    for round in 0..64u64 {
+        let start = unsafe { _rdtsc() };
+        for i in 0..(round % 7 + 1) * 8 {
+            unsafe { core::ptr::read_volatile(&i) };
+        }
+        let delta = unsafe { _rdtsc() }.wrapping_sub(start);
+        value = value.rotate_left(5) ^ delta;
+    }
+    let mut state = value ^ unsafe { _rdtsc() };
+    splitmix64(&mut state)
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Chooses virtual addresses that don't collide with each other or existing mappings.
+///
+/// Every chosen region gets its own level 4 entries, so regions never share page tables. The
+/// addresses are only random after [`randomize`](Layout::randomize) was called, otherwise the
+/// first free entries are used, so that the layout is the same on every boot.
+pub(crate) struct Layout {
+    used: [bool; 512],
+    rng: Option<Rng>,
+}
+
+impl Layout {
+    /// Creates a layout that avoids the level 4 entries that are in use in `p4`.
+    pub(crate) fn new(p4: &PageTable) -> Self {
+        let mut used = [false; 512];
+        for (index, used) in used.iter_mut().enumerate() {
+            *used = !p4[index].is_unused();
+        }
+        Layout { used, rng: None }
+    }
+
+    /// Chooses random addresses from now on.
+    pub(crate) fn randomize(&mut self) {
+        self.rng = Some(Rng::new());
+    }
+
+    /// Returns a random number in `0..bound`, or zero if the layout is not randomized.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.rng.as_mut().map_or(0, |rng| rng.below(bound))
+    }
+
+    /// Marks the level 4 entries that contain the range `start..start + size` as used.
+    pub(crate) fn reserve(&mut self, start: u64, size: u64) {
+        if size == 0 {
+            return;
+        }
+        let first = p4_index(start);
+        let last = p4_index(start + (size - 1));
+        for index in first..=last {
+            self.used[index] = true;
+        }
+    }
+
+    /// Chooses an `align`ed address for a region of `size` bytes in the requested half of the
+    /// address space.
+    pub(crate) fn choose(&mut self, size: u64, align: u64, higher_half: bool) -> Option<u64> {
+        let entries = (size.checked_add(P4_ENTRY_SIZE - 1)? / P4_ENTRY_SIZE) as usize;
+        let entries = entries.max(1);
+        let (first, end) = if higher_half { (256, 512) } else { (0, 256) };
+        if entries > end - first {
+            return None;
+        }
+        let is_free =
+            |used: &[bool; 512], start: usize| used[start..start + entries].iter().all(|u| !u);
+
+        let candidates = (first..=end - entries)
+            .filter(|&start| is_free(&self.used, start))
+            .count();
+        if candidates == 0 {
+            return None;
+        }
+        let choice = self.below(candidates as u64) as usize;
+        let start_index = (first..=end - entries)
+            .filter(|&start| is_free(&self.used, start))
+            .nth(choice)?;
+        for index in start_index..start_index + entries {
+            self.used[index] = true;
+        }
+
+        // randomize the offset inside the entry if the region is small enough
+        let slack = entries as u64 * P4_ENTRY_SIZE - size;
+        let offset = self.below(slack / align + 1) * align;
+
+        let zero = u9::new(0);
+        let entry_start =
+            Page::from_page_table_indices(u9::new(start_index as u16), zero, zero, zero);
+        Some(entry_start.start_address().as_u64() + offset)
+    }
+}
+
+fn p4_index(addr: u64) -> usize {
+    ((addr >> 39) & 0o777) as usize
+}
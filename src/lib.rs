@@ -11,6 +11,7 @@
 pub use crate::bootinfo::BootInfo;
 
 pub mod bootinfo;
+pub mod config;
 
 /// Defines the entry point function.
 ///
@@ -8,19 +8,17 @@
 #![no_std]
 #![no_main]
 
-use bootloader::bootinfo::{BootInfo, FrameRange};
+use bootloader::bootinfo::{BootInfo, FrameRange, TlsTemplate, VirtualRange};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use core::{mem, slice};
-use fixedvec::alloc_stack;
 use usize_conversions::usize_from;
-use x86_64::structures::paging::{Mapper, RecursivePageTable};
-use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, PhysFrameRange, Size4KiB, Size2MiB};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, PhysFrameRange};
+use x86_64::structures::paging::{Size1GiB, Size2MiB, Size4KiB};
 use x86_64::ux::u9;
 use x86_64::{PhysAddr, VirtAddr};
 
-/// The offset into the virtual address space where the physical memory is mapped if
-/// the `map_physical_memory` is activated.
-const PHYSICAL_MEMORY_OFFSET: u64 = 0o_177777_770_000_000_000_0000;
 use apic::{LocalApic, XApic};
 
 global_asm!(include_str!("boot_ap.s"));
@@ -28,22 +26,25 @@ global_asm!(include_str!("stage_1.s"));
 global_asm!(include_str!("stage_2.s"));
 global_asm!(include_str!("e820.s"));
 global_asm!(include_str!("stage_3.s"));
+global_asm!(include_str!("handoff.s"));
 
 #[cfg(feature = "vga_320x200")]
 global_asm!(include_str!("video_mode/vga_320x200.s"));
 #[cfg(not(feature = "vga_320x200"))]
 global_asm!(include_str!("video_mode/vga_text_80x25.s"));
 
-unsafe fn context_switch(boot_info: VirtAddr, entry_point: VirtAddr, stack_pointer: VirtAddr) -> ! {
-    asm!("jmp $1; ${:private}.spin.${:uid}: jmp ${:private}.spin.${:uid}" ::
-         "{rsp}"(stack_pointer), "r"(entry_point), "{rdi}"(boot_info) :: "intel");
-    ::core::hint::unreachable_unchecked()
-}
-
+mod boot_config;
 mod boot_info;
 mod frame_allocator;
+mod handoff;
+mod kaslr;
+mod offset_page_table;
 mod page_table;
 mod printer;
+mod relocation;
+mod segments;
+mod tls;
+mod validation;
 
 pub struct IdentityMappedAddr(PhysAddr);
 
@@ -61,32 +62,52 @@ impl IdentityMappedAddr {
     }
 }
 
-// Set by first core
-const BOOT_INFO_ADDR: u64 = 0xb0071f0000;
-static mut ENTRY_POINT: u64 = 0;
-static mut KSTACK_TOP: u64 = 0;
-static mut BOOTING_CORE_ID: u8 = 0;
-
-unsafe fn get_kstack_top(core_id: u8) -> VirtAddr {
-    VirtAddr::new(KSTACK_TOP - 0x10000 * core_id as u64)
-}
+/// The virtual address at which position independent kernels are loaded.
+const PIE_KERNEL_LOAD_BASE: u64 = 0xffff_8000_0000_0000;
+/// The virtual address at which the VGA text buffer is mapped for the kernel.
+const VGA_BUFFER_ADDR: u64 = bootloader::config::layout::VGA_BUFFER_ADDR;
+/// The maximum number of processors that are started, including the bootstrap processor.
+const MAX_PROCESSORS: u64 = bootloader::config::MAX_PROCESSORS;
+
+// Set by first core, which publishes them with `Release` before it starts the other cores
+static BOOT_INFO_ADDR: AtomicU64 = AtomicU64::new(0);
+static ENTRY_POINT: AtomicU64 = AtomicU64::new(0);
+static BOOTING_CORE_ID: AtomicU8 = AtomicU8::new(0);
+// The stack top for the next booting core
+static KSTACK_TOP: AtomicU64 = AtomicU64::new(0);
+// The thread pointer for the next booting core, or zero if the kernel gets no TLS blocks
+static TLS_THREAD_POINTER: AtomicU64 = AtomicU64::new(0);
 
 #[no_mangle]
 pub unsafe extern "C" fn other_main() {
     enable_nxe_bit();
     enable_write_protect_bit();
-    let core_id = BOOTING_CORE_ID;
+    let stack_top = VirtAddr::new(KSTACK_TOP.load(Ordering::Acquire));
+    // Tell the first core to map a new stack for the next core
+    KSTACK_TOP.store(0, Ordering::Release);
+    let thread_pointer = TLS_THREAD_POINTER.load(Ordering::Acquire);
+    if thread_pointer != 0 {
+        tls::set_thread_pointer(VirtAddr::new(thread_pointer));
+        // Tell the first core to prepare a new block for the next core
+        TLS_THREAD_POINTER.store(0, Ordering::Release);
+    }
+    let boot_info = VirtAddr::new(BOOT_INFO_ADDR.load(Ordering::Acquire));
+    let entry_point = VirtAddr::new(ENTRY_POINT.load(Ordering::Acquire));
     // Notify this core booting end
-    BOOTING_CORE_ID += 1;
-    let stack_top = get_kstack_top(core_id);
-    context_switch(VirtAddr::new(BOOT_INFO_ADDR), VirtAddr::new(ENTRY_POINT), stack_top);
+    BOOTING_CORE_ID.fetch_add(1, Ordering::Release);
+    handoff::enter_kernel_on_other_processor(boot_info, entry_point, stack_top);
 }
 
-// Symbols defined in `linker.ld`
+// Symbols defined in `linker.ld` and the assembly stages
 extern "C" {
     static mmap_ent: usize;
+    static kernel_load_addr: u64;
     static _memory_map: usize;
     static _kib_kernel_size: usize;
+    static _kib_physical_memory_mmio_size: u64;
+    static _kib_physical_memory_offset: u64;
+    static _kib_boot_info_addr: u64;
+    static _kib_overrides: u64;
     static __page_table_start: usize;
     static __page_table_end: usize;
     static __bootloader_end: usize;
@@ -99,7 +120,7 @@ pub unsafe extern "C" fn stage_4() -> ! {
     asm!("mov bx, 0x0
           mov ss, bx" ::: "bx" : "intel");
 
-    let kernel_start = 0x400000;
+    let kernel_start = kernel_load_addr;
     let kernel_size = _kib_kernel_size as u64;
     let memory_map_addr = &_memory_map as *const _ as u64;
     let memory_map_entry_count = (mmap_ent & 0xff) as u64; // Extract lower 8 bits
@@ -131,8 +152,8 @@ fn load_elf(
     bootloader_end: PhysAddr,
 ) -> ! {
     use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
-    use fixedvec::FixedVec;
-    use xmas_elf::program::{ProgramHeader, ProgramHeader64};
+    use bootloader::config::{Config, FramebufferMode};
+    use xmas_elf::header;
 
     printer::Printer.clear_screen();
 
@@ -144,42 +165,254 @@ fn load_elf(
         .max()
         .expect("no physical memory regions found");
 
+    // The level 4 table is recursively mapped by stage 3. The entry is removed before the
+    // kernel is mapped, so kernel segments may lie in its region.
+    let stage_3_recursive_index = u9::new(511);
+    let stage_3_p4_addr = Page::from_page_table_indices(
+        stage_3_recursive_index,
+        stage_3_recursive_index,
+        stage_3_recursive_index,
+        stage_3_recursive_index,
+    ).start_address();
+
+    // Keeps track of used address space, so that free regions can be chosen for the mappings
+    // of the bootloader. The choice is only random if the kernel requests it.
+    let mut layout = kaslr::Layout::new(unsafe { &*stage_3_p4_addr.as_ptr() });
+    layout.reserve(VGA_BUFFER_ADDR, Size4KiB::SIZE);
+
     // Extract required information from the ELF file.
-    let mut preallocated_space = alloc_stack!([ProgramHeader64; 32]);
-    let mut segments = FixedVec::new(&mut preallocated_space);
+    let mut segments;
+    let mut config: Config;
+    let kernel_recursive_index;
+    let entry_point;
+    let mut kernel_load_base = 0;
     {
         let kernel_start_ptr = usize_from(kernel_start.as_u64()) as *const u8;
         let kernel = unsafe { slice::from_raw_parts(kernel_start_ptr, usize_from(kernel_size)) };
-        let elf_file = xmas_elf::ElfFile::new(kernel).unwrap();
-        xmas_elf::header::sanity_check(&elf_file).unwrap();
+        let elf_file = xmas_elf::ElfFile::new(kernel)
+            .unwrap_or_else(|err| panic!("invalid kernel executable: {}", err));
+        validation::check_header(&elf_file);
 
-        unsafe { ENTRY_POINT = elf_file.header.pt2.entry_point(); }
+        config = boot_config::load(&elf_file);
+        if config.kaslr {
+            layout.randomize();
+        }
+        // The kernel chooses the index of its recursive entry, which is independent of the
+        // one used by stage 3.
+        kernel_recursive_index = if cfg!(feature = "recursive_page_table") {
+            Some(u9::new(config.recursive_index as u16))
+        } else {
+            None
+        };
+        if let Some(index) = kernel_recursive_index {
+            layout.reserve(p4_entry_start(index).as_u64(), 512 * Size1GiB::SIZE);
+        }
+        check_no_recursive_overlap(
+            "VGA buffer mapping",
+            VGA_BUFFER_ADDR,
+            Size4KiB::SIZE,
+            kernel_recursive_index,
+        );
+
+        // Position independent kernels are linked at address zero and need to be moved.
+        if elf_file.header.pt2.type_().as_type() == header::Type::SharedObject {
+            kernel_load_base = if config.kaslr {
+                random_kernel_load_base(&elf_file, &mut layout)
+            } else {
+                PIE_KERNEL_LOAD_BASE
+            };
+        }
+        entry_point = kernel_load_base + elf_file.header.pt2.entry_point();
+
+        segments = segments::Segments::new(&elf_file, kernel_load_base);
+        validation::check_segments(
+            segments,
+            entry_point,
+            config.deny_writable_executable,
+            config.copy_kernel_segments,
+            kernel_recursive_index.map(|index| u64::from(u16::from(index))),
+        );
+        check_no_segment_overlap(
+            "VGA buffer mapping",
+            VGA_BUFFER_ADDR,
+            Size4KiB::SIZE,
+            segments,
+        );
+    }
 
-        for program_header in elf_file.program_iter() {
-            match program_header {
-                ProgramHeader::Ph64(header) => segments
-                    .push(*header)
-                    .expect("does not support more than 32 program segments"),
-                ProgramHeader::Ph32(_) => panic!("does not support 32 bit elf files"),
-            }
+    let tls_template = segments
+        .iter()
+        .find(|segment| segment.get_type() == Ok(xmas_elf::program::Type::Tls))
+        .map(|segment| TlsTemplate {
+            start_addr: segment.virtual_addr,
+            file_size: segment.file_size,
+            mem_size: segment.mem_size,
+            align: segment.align,
+        });
+    // Only set up TLS blocks if the kernel uses thread local storage.
+    let tls_template_for_blocks = tls_template.filter(|_| config.initialize_tls);
+    let mut tls_area_start = None;
+
+    // The video mode is set up by the assembly stages, so it can't be switched at runtime.
+    let framebuffer = if cfg!(feature = "vga_320x200") {
+        FramebufferMode::Vga320x200
+    } else {
+        FramebufferMode::VgaText80x25
+    };
+    assert!(
+        config.framebuffer == framebuffer,
+        "kernel requests framebuffer mode {:?}, but the bootloader was built for {:?}",
+        config.framebuffer,
+        framebuffer
+    );
+
+    let map_physical_memory = config.map_physical_memory || cfg!(feature = "map_physical_memory");
+
+    // The stacks of all processors lie in one area, which must fit into a level 4 entry if the
+    // bootloader chooses its address.
+    let stack_area_size =
+        page_table::KernelStacks::area_size(config.kernel_stack_size, MAX_PROCESSORS)
+            .filter(|&size| size <= 512 * Size1GiB::SIZE - Size4KiB::SIZE)
+            .unwrap_or_else(|| {
+                panic!(
+                    "kernel stacks of {} pages for {} processors don't fit into 512GiB",
+                    config.kernel_stack_size, MAX_PROCESSORS
+                )
+            });
+
+    for segment in segments.iter() {
+        if segment.get_type() == Ok(xmas_elf::program::Type::Load) {
+            layout.reserve(segment.virtual_addr, segment.mem_size);
         }
     }
 
+    if config.kaslr {
+        let higher_half = entry_point >= 0xffff_8000_0000_0000;
+        let mut choose = |size, align| {
+            layout
+                .choose(size, align, higher_half)
+                .expect("no free virtual memory region for randomized address")
+        };
+        config.boot_info_address = choose(Size4KiB::SIZE, Size4KiB::SIZE);
+        // leave space for the guard page
+        config.kernel_stack_address = choose(stack_area_size, Size4KiB::SIZE) + Size4KiB::SIZE;
+        if map_physical_memory {
+            let physical_memory_size = x86_64::align_up(
+                max_phys_addr + config.physical_memory_mmio_size,
+                Size1GiB::SIZE,
+            );
+            config.physical_memory_offset = choose(physical_memory_size, Size1GiB::SIZE);
+        }
+        if let Some(template) = tls_template_for_blocks {
+            let size = tls::TlsBlocks::area_size(&template, MAX_PROCESSORS);
+            tls_area_start = Some(choose(size, tls::TlsBlocks::area_align(&template)));
+        }
+    }
+
+    let physical_memory_offset = config.physical_memory_offset;
+    // 1GiB pages can only be used if the offset is aligned accordingly.
+    let physical_memory_1gib_pages =
+        supports_1gib_pages() && physical_memory_offset % Size1GiB::SIZE == 0;
+    // The mapping covers whole pages, so check and reserve the rounded up size.
+    let physical_memory_size = x86_64::align_up(
+        max_phys_addr + config.physical_memory_mmio_size,
+        if physical_memory_1gib_pages {
+            Size1GiB::SIZE
+        } else {
+            Size2MiB::SIZE
+        },
+    );
+    let boot_info_addr = VirtAddr::new(config.boot_info_address);
+
+    // Make sure that the configured addresses do not collide with the kernel.
+    assert!(
+        boot_info_addr.as_u64() % Size4KiB::SIZE == 0,
+        "boot info address {:#x} is not page aligned",
+        boot_info_addr.as_u64()
+    );
+    check_no_segment_overlap(
+        "boot info",
+        boot_info_addr.as_u64(),
+        Size4KiB::SIZE,
+        segments,
+    );
+    check_no_recursive_overlap(
+        "boot info",
+        boot_info_addr.as_u64(),
+        Size4KiB::SIZE,
+        kernel_recursive_index,
+    );
+    check_no_vga_overlap("boot info", boot_info_addr.as_u64(), Size4KiB::SIZE);
+    assert!(config.kernel_stack_size > 0, "kernel stack size must not be zero");
+    if config.kernel_stack_address != 0 {
+        assert!(
+            config.kernel_stack_address % Size4KiB::SIZE == 0,
+            "kernel stack address {:#x} is not page aligned",
+            config.kernel_stack_address
+        );
+        // include the guard page below the stack
+        check_no_segment_overlap(
+            "kernel stacks",
+            config.kernel_stack_address - Size4KiB::SIZE,
+            stack_area_size,
+            segments,
+        );
+        check_no_recursive_overlap(
+            "kernel stacks",
+            config.kernel_stack_address - Size4KiB::SIZE,
+            stack_area_size,
+            kernel_recursive_index,
+        );
+        check_no_vga_overlap(
+            "kernel stacks",
+            config.kernel_stack_address - Size4KiB::SIZE,
+            stack_area_size,
+        );
+    }
+    if map_physical_memory {
+        assert!(
+            physical_memory_offset % Size2MiB::SIZE == 0,
+            "physical memory offset {:#x} is not 2MiB aligned",
+            physical_memory_offset
+        );
+        check_no_segment_overlap(
+            "physical memory mapping",
+            physical_memory_offset,
+            physical_memory_size,
+            segments,
+        );
+        check_no_recursive_overlap(
+            "physical memory mapping",
+            physical_memory_offset,
+            physical_memory_size,
+            kernel_recursive_index,
+        );
+        check_no_vga_overlap(
+            "physical memory mapping",
+            physical_memory_offset,
+            physical_memory_size,
+        );
+        let boot_info_offset = boot_info_addr.as_u64().wrapping_sub(physical_memory_offset);
+        assert!(
+            boot_info_offset >= physical_memory_size,
+            "boot info address {:#x} lies inside the physical memory mapping",
+            boot_info_addr.as_u64()
+        );
+    }
+
+    // Other regions that are only needed while loading the kernel must not collide with the
+    // configured addresses.
+    layout.reserve(boot_info_addr.as_u64(), Size4KiB::SIZE);
+    if config.kernel_stack_address != 0 {
+        layout.reserve(config.kernel_stack_address - Size4KiB::SIZE, stack_area_size);
+    }
+    if map_physical_memory {
+        layout.reserve(physical_memory_offset, physical_memory_size);
+    }
+
     // Enable support for the no-execute bit in page tables.
     enable_nxe_bit();
 
-    // Create a RecursivePageTable
-    let recursive_index = u9::new(511);
-    let recursive_page_table_addr = Page::from_page_table_indices(
-        recursive_index,
-        recursive_index,
-        recursive_index,
-        recursive_index,
-    ).start_address();
-    let page_table = unsafe { &mut *(recursive_page_table_addr.as_mut_ptr()) };
-    let mut rec_page_table =
-        RecursivePageTable::new(page_table).expect("recursive page table creation failed");
-
     // Create a frame allocator, which marks allocated frames as used in the memory map.
     let mut frame_allocator = frame_allocator::FrameAllocator {
         memory_map: &mut memory_map,
@@ -198,7 +431,7 @@ fn load_elf(
             PhysFrame::range(bootloader_start_frame, bootloader_end_frame + 1);
         frame_allocator.mark_allocated_region(MemoryRegion {
             range: frame_range(bootloader_memory_area),
-            region_type: MemoryRegionType::Bootloader,
+            region_type: MemoryRegionType::BootloaderReclaimable,
         });
         let kernel_start_frame = PhysFrame::containing_address(kernel_start.phys());
         let kernel_end_frame =
@@ -208,106 +441,288 @@ fn load_elf(
             range: frame_range(kernel_memory_area),
             region_type: MemoryRegionType::Kernel,
         });
+        // The page tables of stage 3 are replaced before the kernel is entered.
         let page_table_start_frame = PhysFrame::containing_address(page_table_start);
         let page_table_end_frame = PhysFrame::containing_address(page_table_end - 1u64);
         let page_table_memory_area =
             PhysFrame::range(page_table_start_frame, page_table_end_frame + 1);
         frame_allocator.mark_allocated_region(MemoryRegion {
             range: frame_range(page_table_memory_area),
-            region_type: MemoryRegionType::PageTable,
+            region_type: MemoryRegionType::BootloaderReclaimable,
         });
     }
 
+    // Access the page tables through a mapping of the physical memory. The recursive entry of
+    // stage 3 is only used for creating this mapping and removed afterwards.
+    let physical_memory_window = layout
+        .choose(
+            x86_64::align_up(max_phys_addr, 512 * Size1GiB::SIZE),
+            512 * Size1GiB::SIZE,
+            false,
+        )
+        .expect("no free virtual memory region for the physical memory mapping");
+    let mut page_table = unsafe {
+        offset_page_table::OffsetPageTable::new(
+            stage_3_recursive_index,
+            physical_memory_window,
+            PhysAddr::new(max_phys_addr),
+            &mut frame_allocator,
+        )
+    }
+    .expect("physical memory mapping failed");
+
+    // Keep the program headers accessible after the ELF file is unmapped.
+    segments.move_table(&page_table);
+
+    // Map the trampoline for the final jump to the higher half, so that it stays accessible
+    // when the bootloader region is unmapped.
+    let handoff_window = layout
+        .choose(handoff::size(), Size4KiB::SIZE, true)
+        .expect("no free virtual memory region for the handoff trampoline");
+    let handoff_region = handoff::map(
+        Page::containing_address(VirtAddr::new(handoff_window)),
+        &mut page_table,
+        &mut frame_allocator,
+    )
+    .expect("handoff trampoline mapping failed");
+
     // Unmap the ELF file.
     let kernel_start_page: Page<Size2MiB> = Page::containing_address(kernel_start.virt());
     let kernel_end_page: Page<Size2MiB> =
         Page::containing_address(kernel_start.virt() + kernel_size - 1u64);
     for page in Page::range_inclusive(kernel_start_page, kernel_end_page) {
-        rec_page_table.unmap(page).expect("dealloc error").1.flush();
+        page_table.unmap(page).expect("dealloc error");
     }
+    page_table::remove_empty_identity_tables(&mut page_table);
 
     // Map kernel segments.
-    let stack_end = page_table::map_kernel(
-        kernel_start.phys(),
-        &segments,
-        &mut rec_page_table,
-        &mut frame_allocator,
-    )
-    .expect("kernel mapping failed");
-    unsafe { KSTACK_TOP = stack_end.as_u64(); }
+    if config.copy_kernel_segments {
+        page_table::copy_kernel(
+            kernel_start.phys(),
+            segments,
+            &mut page_table,
+            &mut frame_allocator,
+        )
+        .expect("kernel mapping failed");
+    } else {
+        page_table::map_kernel(
+            kernel_start.phys(),
+            segments,
+            &mut page_table,
+            &mut frame_allocator,
+        )
+        .expect("kernel mapping failed");
+    }
+    if kernel_load_base != 0 {
+        relocation::apply(segments, kernel_load_base);
+    }
+    page_table::apply_relro(segments, &mut page_table);
+    BOOT_INFO_ADDR.store(boot_info_addr.as_u64(), Ordering::Release);
+    ENTRY_POINT.store(entry_point, Ordering::Release);
+
+    // Flags for the data regions that the bootloader maps for the kernel.
+    let mut data_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if config.deny_writable_executable {
+        data_flags |= PageTableFlags::NO_EXECUTE;
+    }
 
     // Map a page for the boot info structure
     let boot_info_page = {
-        let page: Page = Page::containing_address(VirtAddr::new(BOOT_INFO_ADDR));
+        let page: Page = Page::containing_address(boot_info_addr);
         let frame = frame_allocator
             .allocate_frame(MemoryRegionType::BootInfo)
             .expect("frame allocation failed");
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        rec_page_table.map_to(page, frame, flags, &mut frame_allocator)
-            .expect("Mapping of bootinfo page failed")
-            .flush();
+        page_table.map_to(page, frame, data_flags, &mut frame_allocator)
+            .expect("Mapping of bootinfo page failed");
         page
     };
 
-    if cfg!(feature = "map_physical_memory") {
-        fn virt_for_phys(phys: PhysAddr) -> VirtAddr {
-            VirtAddr::new(phys.as_u64() + PHYSICAL_MEMORY_OFFSET)
-        }
-
-        let start_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0));
-        let end_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(max_phys_addr));
-        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-            let page = Page::containing_address(virt_for_phys(frame.start_address()));
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            rec_page_table
-                .map_to(page, frame, flags, &mut frame_allocator)
-                .expect("Mapping of bootinfo page failed")
-                .flush();
-        }
+    if map_physical_memory {
+        // Also map the configured amount of address space above the highest RAM address, so
+        // that the kernel can access memory mapped devices (e.g. the local APIC or PCI BARs).
+        let end = PhysAddr::new(physical_memory_size);
+        let result = if physical_memory_1gib_pages {
+            page_table::map_physical_memory::<Size1GiB>(
+                end,
+                physical_memory_offset,
+                data_flags,
+                &mut page_table,
+                &mut frame_allocator,
+            )
+        } else {
+            page_table::map_physical_memory::<Size2MiB>(
+                end,
+                physical_memory_offset,
+                data_flags,
+                &mut page_table,
+                &mut frame_allocator,
+            )
+        };
+        result.expect("Mapping of physical memory failed");
     }
 
     // Map VGA 0xb8000 to kernel P4 area
     // TODO: choose a better virtual address
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    rec_page_table.map_to(
-        Page::containing_address(VirtAddr::new(0xffffff00_f0000000)),
+    page_table.map_to(
+        Page::containing_address(VirtAddr::new(VGA_BUFFER_ADDR)),
         PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0xb8000)),
-        flags, &mut frame_allocator).unwrap().flush();
+        data_flags, &mut frame_allocator).unwrap();
+
+    // Map the kernel stack. This happens after all other mappings, so that a free region can be
+    // chosen if the kernel didn't request a specific address.
+    let stack_start = match config.kernel_stack_address {
+        0 => {
+            let p4 = page_table.level_4_table();
+            let higher_half = entry_point >= 0xffff_8000_0000_0000;
+            page_table::find_free_region(p4, higher_half, kernel_recursive_index)
+                .expect("no free virtual memory region for the kernel stack")
+        }
+        addr => VirtAddr::new(addr),
+    };
+    // Like on Linux, the stack is only executable if the kernel doesn't request otherwise.
+    let gnu_stack = segments
+        .iter()
+        .find(|segment| segment.get_type() == Ok(xmas_elf::program::Type::GnuStack));
+    let executable_stack = if config.deny_writable_executable {
+        assert!(
+            !gnu_stack.map_or(false, |segment| segment.flags.is_execute()),
+            "kernel requests an executable stack, but writable and executable mappings are denied"
+        );
+        false
+    } else {
+        gnu_stack.map_or(true, |segment| segment.flags.is_execute())
+    };
+    let global_stacks =
+        config.global_kernel_mappings && stack_start.as_u64() >= 0xffff_8000_0000_0000;
+    let mut kernel_stacks = page_table::KernelStacks::new(
+        stack_start,
+        config.kernel_stack_size,
+        executable_stack,
+        global_stacks,
+    );
+    let (_, stack_end) = kernel_stacks
+        .map_next(&mut page_table, &mut frame_allocator)
+        .expect("kernel stack mapping failed");
+
+    // Set up the TLS block of this core. The blocks of the other cores are set up on demand.
+    let mut tls_blocks = tls_template_for_blocks.map(|template| {
+        let area_start = tls_area_start.unwrap_or_else(|| {
+            let p4 = page_table.level_4_table();
+            let higher_half = entry_point >= 0xffff_8000_0000_0000;
+            let addr = page_table::find_free_region(p4, higher_half, kernel_recursive_index)
+                .expect("no free virtual memory region for the TLS blocks");
+            x86_64::align_up(addr.as_u64(), tls::TlsBlocks::area_align(&template))
+        });
+        let global = config.global_kernel_mappings && area_start >= 0xffff_8000_0000_0000;
+        tls::TlsBlocks::new(template, area_start, global)
+    });
+    if let Some(ref mut tls_blocks) = tls_blocks {
+        let thread_pointer = tls_blocks
+            .map_next(&mut page_table, &mut frame_allocator)
+            .expect("TLS block mapping failed");
+        unsafe { tls::set_thread_pointer(thread_pointer) };
+    }
+
+    if config.global_kernel_mappings {
+        page_table::make_higher_half_global(&mut page_table);
+    }
+
+    let other_processors = start_other_processor(
+        &mut page_table,
+        &mut frame_allocator,
+        &mut kernel_stacks,
+        tls_blocks.as_mut(),
+    );
+
+    if config.copy_kernel_segments {
+        // The kernel executable is no longer needed by the bootloader, as all segments were
+        // copied.
+        let kernel_start_frame = PhysFrame::containing_address(kernel_start.phys());
+        let kernel_end_frame =
+            PhysFrame::containing_address(kernel_start.phys() + kernel_size - 1u64);
+        frame_allocator.change_region_type(
+            PhysFrame::range(kernel_start_frame, kernel_end_frame + 1),
+            MemoryRegionType::Kernel,
+            MemoryRegionType::KernelImageReclaimable,
+        );
+    }
 
-    start_other_processor(&mut rec_page_table, &mut frame_allocator);
+    // Create the page tables of the kernel, which don't map the bootloader region.
+    let kernel_page_table = page_table::create_kernel_page_table(
+        kernel_recursive_index,
+        &mut page_table,
+        &mut frame_allocator,
+    )
+    .expect("kernel page table creation failed");
 
     // Construct boot info structure.
-    let mut boot_info = BootInfo::new(memory_map, recursive_page_table_addr.as_u64(), PHYSICAL_MEMORY_OFFSET);
+    let recursive_page_table_addr = kernel_recursive_index.map_or(0, |index| {
+        Page::<Size4KiB>::from_page_table_indices(index, index, index, index)
+            .start_address()
+            .as_u64()
+    });
+    let physical_memory_offset = if map_physical_memory {
+        physical_memory_offset
+    } else {
+        0
+    };
+    let mut boot_info = BootInfo::new(memory_map, recursive_page_table_addr, physical_memory_offset);
     boot_info.memory_map.sort();
+    boot_info.kernel_stack_bottom = stack_start.as_u64();
+    boot_info.kernel_stack_top = stack_end.as_u64();
+    boot_info.processor_count = other_processors + 1;
+    boot_info.set_kernel_stack_stride(kernel_stacks.stride());
+    boot_info.kernel_load_base = kernel_load_base;
+    boot_info.handoff_region = handoff_region;
+    if let Some(template) = tls_template {
+        boot_info.set_tls_template(template);
+    }
+    page_table::find_writable_executable(&mut page_table, |start, end| {
+        // The bootloader region is not mapped in the page tables of the kernel.
+        let start = start.as_u64().max(validation::BOOTLOADER_REGION_END);
+        if start < end.as_u64() {
+            boot_info.add_writable_executable_region(VirtualRange {
+                start_addr: start,
+                end_addr: end.as_u64(),
+            })
+        }
+    });
 
     // Write boot info to boot info page.
-    unsafe { (BOOT_INFO_ADDR as *mut BootInfo).write(boot_info) };
+    unsafe { boot_info_addr.as_mut_ptr::<BootInfo>().write(boot_info) };
 
     // Make sure that the kernel respects the write-protection bits, even when in ring 0.
     enable_write_protect_bit();
 
-    if cfg!(not(feature = "recursive_page_table")) {
-        // unmap recursive entry
-        rec_page_table
-            .unmap(Page::<Size4KiB>::containing_address(recursive_page_table_addr))
-            .expect("error deallocating recursive entry")
-            .1
-            .flush();
-        mem::drop(rec_page_table);
-    }
+    mem::drop(page_table);
 
-    unsafe { context_switch(VirtAddr::new(BOOT_INFO_ADDR), VirtAddr::new(ENTRY_POINT), stack_end) };
+    unsafe {
+        handoff::enter_kernel(
+            boot_info_addr,
+            VirtAddr::new(entry_point),
+            stack_end,
+            kernel_page_table,
+            other_processors,
+        )
+    };
 }
 
-fn start_other_processor(page_table: &mut RecursivePageTable, frame_allocator: &mut frame_allocator::FrameAllocator) {
+fn start_other_processor(
+    page_table: &mut offset_page_table::OffsetPageTable,
+    frame_allocator: &mut frame_allocator::FrameAllocator,
+    kernel_stacks: &mut page_table::KernelStacks,
+    mut tls_blocks: Option<&mut tls::TlsBlocks>,
+) -> u64 {
+    let mut started = 0;
+
     // Map zero & local apic temporarily
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
     page_table.identity_map(
         PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0)),
-        flags, frame_allocator).unwrap().flush();
+        flags, frame_allocator).unwrap();
     page_table.identity_map(
         PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0xfee00000)),
-        flags, frame_allocator).unwrap().flush();
+        flags, frame_allocator).unwrap();
 
     // Start other processors
     unsafe {
@@ -315,21 +730,144 @@ fn start_other_processor(page_table: &mut RecursivePageTable, frame_allocator: &
         let mut apic = XApic::new(0xfee00000);
 
         // TODO: Use `acpi` crate to count processors
-        for i in 1..128 {
-            BOOTING_CORE_ID = i;
+        for i in 1..MAX_PROCESSORS as u8 {
+            // The previous stack is reused if the previous core didn't start.
+            if KSTACK_TOP.load(Ordering::Acquire) == 0 {
+                let (_, stack_top) = kernel_stacks
+                    .map_next(page_table, frame_allocator)
+                    .expect("kernel stack mapping failed");
+                KSTACK_TOP.store(stack_top.as_u64(), Ordering::Release);
+            }
+            if let Some(ref mut tls_blocks) = tls_blocks {
+                // The previous block is reused if the previous core didn't start.
+                if TLS_THREAD_POINTER.load(Ordering::Acquire) == 0 {
+                    let thread_pointer = tls_blocks
+                        .map_next(page_table, frame_allocator)
+                        .expect("TLS block mapping failed");
+                    TLS_THREAD_POINTER.store(thread_pointer.as_u64(), Ordering::Release);
+                }
+            }
+            BOOTING_CORE_ID.store(i, Ordering::Release);
             apic.start_ap(i, 0x8000);
 
             const TIMEOUT: usize = 1_000_000;
             let mut count = 0;
-            while count < TIMEOUT && core::ptr::read_volatile(&BOOTING_CORE_ID) == i {
+            while count < TIMEOUT && BOOTING_CORE_ID.load(Ordering::Acquire) == i {
                 count += 1;
             }
+            if BOOTING_CORE_ID.load(Ordering::Acquire) != i {
+                started += 1;
+            }
         }
     }
 
     // Unmap
-    page_table.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(0))).unwrap().1.flush();
-    page_table.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(0xfee00000))).unwrap().1.flush();
+    page_table.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(0))).unwrap();
+    page_table.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(0xfee00000))).unwrap();
+    started
+}
+
+/// Chooses a random load base for a position independent kernel.
+///
+/// The load base is a multiple of the largest segment alignment.
+fn random_kernel_load_base(elf_file: &xmas_elf::ElfFile, layout: &mut kaslr::Layout) -> u64 {
+    use x86_64::align_down;
+    use xmas_elf::program::Type;
+
+    let mut start = u64::max_value();
+    let mut end = 0;
+    let mut align = Size4KiB::SIZE;
+    for segment in elf_file.program_iter() {
+        if segment.get_type() != Ok(Type::Load) {
+            continue;
+        }
+        start = start.min(segment.virtual_addr());
+        end = end.max(segment.virtual_addr() + segment.mem_size());
+        align = align.max(segment.align());
+    }
+    if end == 0 {
+        return PIE_KERNEL_LOAD_BASE;
+    }
+
+    let start = align_down(start, align);
+    let addr = layout
+        .choose(end - start, align, true)
+        .expect("no free virtual memory region for the kernel");
+    addr - start
+}
+
+/// Panics if the virtual address range `start..start + size` overlaps with a loadable segment.
+fn check_no_segment_overlap(
+    name: &str,
+    start: u64,
+    size: u64,
+    segments: segments::Segments,
+) {
+    use xmas_elf::program::Type;
+
+    let end = start.saturating_add(size);
+    for segment in segments.iter() {
+        match segment.get_type() {
+            Ok(Type::Load) => {}
+            _ => continue,
+        }
+        let segment_end = segment.virtual_addr + segment.mem_size;
+        if start < segment_end && segment.virtual_addr < end {
+            panic!(
+                "{} at {:#x}..{:#x} overlaps with kernel segment at {:#x}..{:#x}",
+                name, start, end, segment.virtual_addr, segment_end
+            );
+        }
+    }
+}
+
+/// Panics if the virtual address range `start..start + size` overlaps with the level 4 entry of
+/// the recursive page table mapping.
+fn check_no_recursive_overlap(name: &str, start: u64, size: u64, recursive_index: Option<u9>) {
+    let index = match recursive_index {
+        Some(index) => u64::from(u16::from(index)),
+        None => return,
+    };
+    if size == 0 {
+        return;
+    }
+    let first = (start >> 39) & 0o777;
+    let last = (start.saturating_add(size - 1) >> 39) & 0o777;
+    if first <= index && index <= last {
+        panic!(
+            "{} at {:#x}..{:#x} collides with the recursive page table mapping (level 4 index {})",
+            name,
+            start,
+            start.saturating_add(size),
+            index
+        );
+    }
+}
+
+/// Panics if the virtual address range `start..start + size` overlaps with the page at which
+/// the VGA text buffer is mapped.
+fn check_no_vga_overlap(name: &str, start: u64, size: u64) {
+    let end = start.saturating_add(size);
+    if start < VGA_BUFFER_ADDR + Size4KiB::SIZE && VGA_BUFFER_ADDR < end {
+        panic!(
+            "{} at {:#x}..{:#x} overlaps with the VGA buffer mapping at {:#x}",
+            name, start, end, VGA_BUFFER_ADDR
+        );
+    }
+}
+
+/// Returns the start address of the region that is mapped by the level 4 entry `index`.
+fn p4_entry_start(index: u9) -> VirtAddr {
+    let zero = u9::new(0);
+    Page::<Size4KiB>::from_page_table_indices(index, zero, zero, zero).start_address()
+}
+
+/// Checks whether the CPU supports 1GiB pages (`pdpe1gb` flag).
+fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
 }
 
 fn enable_nxe_bit() {
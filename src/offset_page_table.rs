@@ -0,0 +1,265 @@
+//! Page table access through a mapping of the complete physical memory.
+//!
+//! The `RecursivePageTable` of the `x86_64` crate requires a recursive level 4 entry, which
+//! occupies 512GiB of the address space while the kernel is loaded. Instead, the bootloader
+//! maps the physical memory at unused level 4 entries and accesses all page tables through
+//! that mapping. The mapping is not part of the page tables of the kernel.
+
+use crate::frame_allocator::FrameAllocator;
+use bootloader::bootinfo::MemoryRegionType;
+use core::ops::Range;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::{Page, PageSize, PageTable, PageTableEntry, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::ux::u9;
+use x86_64::{align_up, PhysAddr, VirtAddr};
+
+/// The size of the virtual memory region of a level 4 entry.
+const P4_ENTRY_SIZE: u64 = 512 * Size1GiB::SIZE;
+
+/// Provides access to the active level 4 page table and the tables it references.
+pub(crate) struct OffsetPageTable {
+    level_4_frame: PhysFrame,
+    offset: u64,
+    p4_entries: usize,
+}
+
+/// The reasons why the entry for a page can't be found.
+enum WalkError {
+    NotMapped,
+    ParentEntryHugePage,
+    FrameAllocationFailed,
+}
+
+impl OffsetPageTable {
+    /// Maps the physical memory below `end` to `offset` and removes the recursive entry at
+    /// `recursive_index`, which is only used for creating the mapping.
+    ///
+    /// The offset must be aligned to 512GiB and the level 4 entries of the mapping must be
+    /// unused. The page tables of the mapping are only used by the bootloader, so they are allocated as
+    /// `BootloaderReclaimable` memory.
+    pub(crate) unsafe fn new(
+        recursive_index: u9,
+        offset: u64,
+        end: PhysAddr,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<Self, MapToError> {
+        fn table<'a>(a: u9, b: u9, c: u9, d: u9) -> &'a mut PageTable {
+            let page = Page::<Size4KiB>::from_page_table_indices(a, b, c, d);
+            unsafe { &mut *page.start_address().as_mut_ptr() }
+        }
+        let mut allocate_table = |entry: &mut PageTableEntry| -> Result<(), MapToError> {
+            let frame = frame_allocator
+                .allocate_frame(MemoryRegionType::BootloaderReclaimable)
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            entry.set_addr(
+                frame.start_address(),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+            Ok(())
+        };
+
+        assert!(offset % P4_ENTRY_SIZE == 0, "unaligned physical memory offset");
+        let r = recursive_index;
+        let first_index = (offset / P4_ENTRY_SIZE % 512) as usize;
+        let p4_entries = (align_up(end.as_u64(), P4_ENTRY_SIZE) / P4_ENTRY_SIZE) as usize;
+        let p4 = table(r, r, r, r);
+        assert!(first_index + p4_entries <= 512);
+        for index in first_index..first_index + p4_entries {
+            assert!(p4[index].is_unused());
+        }
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | PageTableFlags::HUGE_PAGE;
+        let p2_count = align_up(end.as_u64(), Size1GiB::SIZE) / Size1GiB::SIZE;
+        for i in 0..p2_count {
+            let index = u9::new((first_index as u64 + i / 512) as u16);
+            let p3_index = u9::new((i % 512) as u16);
+            if i % 512 == 0 {
+                allocate_table(&mut p4[usize_from_u9(index)])?;
+                let p3 = table(r, r, r, index);
+                tlb::flush(VirtAddr::new(p3 as *const PageTable as u64));
+                p3.zero();
+            }
+            let p3 = table(r, r, r, index);
+            allocate_table(&mut p3[usize_from_u9(p3_index)])?;
+            let p2 = table(r, r, index, p3_index);
+            tlb::flush(VirtAddr::new(p2 as *const PageTable as u64));
+            for (j, entry) in p2.iter_mut().enumerate() {
+                let addr = i * Size1GiB::SIZE + j as u64 * Size2MiB::SIZE;
+                entry.set_addr(PhysAddr::new(addr), flags);
+            }
+        }
+
+        let (level_4_frame, _) = Cr3::read();
+        let page_table = OffsetPageTable {
+            level_4_frame,
+            offset,
+            p4_entries,
+        };
+        page_table.table(level_4_frame.start_address())[usize_from_u9(r)].set_unused();
+        tlb::flush_all();
+        Ok(page_table)
+    }
+
+    /// The level 4 indices of the physical memory mapping.
+    pub(crate) fn offset_indices(&self) -> Range<usize> {
+        let first = (self.offset / P4_ENTRY_SIZE % 512) as usize;
+        first..first + self.p4_entries
+    }
+
+    /// Returns the virtual address at which the passed physical address is accessible.
+    pub(crate) fn phys_to_virt(&self, addr: PhysAddr) -> VirtAddr {
+        VirtAddr::new(self.offset + addr.as_u64())
+    }
+
+    /// Returns the page table in the passed frame.
+    pub(crate) fn table<'a>(&self, addr: PhysAddr) -> &'a mut PageTable {
+        unsafe { &mut *self.phys_to_virt(addr).as_mut_ptr() }
+    }
+
+    /// Returns the active level 4 page table.
+    pub(crate) fn level_4_table<'a>(&self) -> &'a mut PageTable {
+        self.table(self.level_4_frame.start_address())
+    }
+
+    /// Returns the entry that maps `page`, which lies in a level 3, level 2, or level 1 table
+    /// depending on the page size.
+    ///
+    /// Missing parent tables are created if a frame allocator is passed.
+    fn entry<S: PageSize>(
+        &self,
+        page: Page<S>,
+        mut frame_allocator: Option<&mut FrameAllocator>,
+    ) -> Result<&mut PageTableEntry, WalkError> {
+        let page_4kib = Page::<Size4KiB>::containing_address(page.start_address());
+        let indices = [
+            page_4kib.p4_index(),
+            page_4kib.p3_index(),
+            page_4kib.p2_index(),
+            page_4kib.p1_index(),
+        ];
+        let levels = if S::SIZE == Size1GiB::SIZE {
+            2
+        } else if S::SIZE == Size2MiB::SIZE {
+            3
+        } else {
+            4
+        };
+
+        let mut table = self.table(self.level_4_frame.start_address());
+        for &index in &indices[..levels - 1] {
+            let entry = &mut table[usize_from_u9(index)];
+            if entry.is_unused() {
+                let frame_allocator = frame_allocator.as_mut().ok_or(WalkError::NotMapped)?;
+                let frame = frame_allocator
+                    .allocate_frame(MemoryRegionType::PageTable)
+                    .ok_or(WalkError::FrameAllocationFailed)?;
+                entry.set_addr(
+                    frame.start_address(),
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                );
+                table = self.table(frame.start_address());
+                table.zero();
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(WalkError::ParentEntryHugePage);
+            } else {
+                table = self.table(entry.addr());
+            }
+        }
+        Ok(&mut table[usize_from_u9(indices[levels - 1])])
+    }
+
+    /// Maps `page` to `frame` with the passed flags and creates missing page tables.
+    pub(crate) fn map_to<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<(), MapToError> {
+        let entry = self
+            .entry(page, Some(frame_allocator))
+            .map_err(|err| match err {
+                WalkError::NotMapped => unreachable!(),
+                WalkError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+                WalkError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+            })?;
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        entry.set_addr(frame.start_address(), flags | huge_page_flag::<S>());
+        tlb::flush(page.start_address());
+        Ok(())
+    }
+
+    /// Maps `frame` to the page with the same address.
+    pub(crate) fn identity_map<S: PageSize>(
+        &mut self,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<(), MapToError> {
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        self.map_to(page, frame, flags, frame_allocator)
+    }
+
+    /// Removes the mapping of `page` and returns the frame it was mapped to.
+    pub(crate) fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, UnmapError> {
+        let entry = self.entry(page, None).map_err(|err| match err {
+            WalkError::ParentEntryHugePage => UnmapError::ParentEntryHugePage,
+            _ => UnmapError::PageNotMapped,
+        })?;
+        if entry.is_unused() {
+            return Err(UnmapError::PageNotMapped);
+        }
+        let frame = PhysFrame::from_start_address(entry.addr())
+            .map_err(|_| UnmapError::InvalidFrameAddress(entry.addr()))?;
+        entry.set_unused();
+        tlb::flush(page.start_address());
+        Ok(frame)
+    }
+
+    /// Replaces the flags of the mapping of `page`.
+    pub(crate) fn update_flags<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let entry = self
+            .entry(page, None)
+            .map_err(|_| FlagUpdateError::PageNotMapped)?;
+        if entry.is_unused() {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        let addr = entry.addr();
+        entry.set_addr(addr, flags | huge_page_flag::<S>());
+        tlb::flush(page.start_address());
+        Ok(())
+    }
+
+    /// Returns the frame that `page` is mapped to.
+    pub(crate) fn translate_page<S: PageSize>(&self, page: Page<S>) -> Option<PhysFrame<S>> {
+        let entry = self.entry(page, None).ok()?;
+        if entry.is_unused() {
+            return None;
+        }
+        PhysFrame::from_start_address(entry.addr()).ok()
+    }
+}
+
+fn huge_page_flag<S: PageSize>() -> PageTableFlags {
+    if S::SIZE == Size4KiB::SIZE {
+        PageTableFlags::empty()
+    } else {
+        PageTableFlags::HUGE_PAGE
+    }
+}
+
+fn usize_from_u9(index: u9) -> usize {
+    usize::from(u16::from(index))
+}
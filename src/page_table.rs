@@ -1,46 +1,158 @@
 use crate::frame_allocator::FrameAllocator;
+use crate::offset_page_table::OffsetPageTable;
+use crate::segments::Segments;
 use bootloader::bootinfo::MemoryRegionType;
-use fixedvec::FixedVec;
-use x86_64::structures::paging::{self, MapToError, RecursivePageTable, UnmapError};
+use x86_64::structures::paging::{FlagUpdateError, MapToError, UnmapError};
 use x86_64::structures::paging::{
-    Mapper, MapperFlush, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    Page, PageSize, PageTable, PageTableEntry, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+    Size4KiB,
 };
-use x86_64::{align_up, PhysAddr, VirtAddr};
+use core::fmt::Write;
+use core::ptr;
+use x86_64::instructions::tlb;
+use usize_conversions::usize_from;
+use x86_64::ux::u9;
+use x86_64::{align_down, align_up, PhysAddr, VirtAddr};
 use xmas_elf::program::{self, ProgramHeader64};
 
 pub(crate) fn map_kernel(
     kernel_start: PhysAddr,
-    segments: &FixedVec<ProgramHeader64>,
-    page_table: &mut RecursivePageTable,
+    segments: Segments,
+    page_table: &mut OffsetPageTable,
     frame_allocator: &mut FrameAllocator,
-) -> Result<VirtAddr, MapToError> {
-    for segment in segments {
-        map_segment(segment, kernel_start, page_table, frame_allocator)?;
+) -> Result<(), MapToError> {
+    let relro = relro_range(segments);
+    for segment in segments.iter() {
+        map_segment(&segment, kernel_start, relro, page_table, frame_allocator)?;
     }
+    Ok(())
+}
 
-    // create a stack
-    // TODO create a stack range dynamically (based on where the kernel is loaded)
-    let stack_start = Page::containing_address(VirtAddr::new(0x57AC_0000_0000));
-    let stack_size: u64 = 512; // in pages
-    let stack_end = stack_start + stack_size;
+/// Returns a start address for a region (e.g. the kernel stack) that doesn't collide with
+/// existing mappings.
+///
+/// The region is placed into the first unused level 4 entry in the requested half of the
+/// address space, directly above a guard page at the start of the entry. The entry at
+/// `recursive_index` is skipped, as it is reserved for the recursive mapping of the kernel.
+pub(crate) fn find_free_region(
+    p4: &PageTable,
+    higher_half: bool,
+    recursive_index: Option<u9>,
+) -> Option<VirtAddr> {
+    // entry 0 of the lower half contains the identity mapping of the bootloader
+    let indices = if higher_half { 256..512 } else { 1..256 };
+    let recursive_index = recursive_index.map(|index| usize::from(u16::from(index)));
+    indices
+        .filter(|&index| p4[index].is_unused() && Some(index) != recursive_index)
+        .map(|index| {
+            let zero = u9::new(0);
+            let start = Page::from_page_table_indices(u9::new(index as u16), zero, zero, zero);
+            (start + 1).start_address()
+        })
+        .next()
+}
 
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    let region_type = MemoryRegionType::KernelStack;
+/// Maps the kernel stacks of the processors into a contiguous virtual memory area.
+///
+/// The stack of the `n`th started processor starts at `stack_start + n * stride`. Every stack
+/// has an unmapped guard page below it, so that a stack overflow causes a page fault instead
+/// of silently overwriting other memory (e.g. the stack of another processor).
+pub(crate) struct KernelStacks {
+    stack_start: Page,
+    size: u64,
+    flags: PageTableFlags,
+    next: u64,
+}
 
-    for page in Page::range(stack_start, stack_end) {
-        let frame = frame_allocator
-            .allocate_frame(region_type)
+impl KernelStacks {
+    /// Creates an allocator for stacks of `size` pages, the first of which starts at
+    /// `stack_start`.
+    ///
+    /// The stacks are only executable if `executable` is set and mapped as global pages if
+    /// `global` is set.
+    pub(crate) fn new(stack_start: VirtAddr, size: u64, executable: bool, global: bool) -> Self {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if !executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if global {
+            flags |= PageTableFlags::GLOBAL;
+        }
+        KernelStacks {
+            stack_start: Page::containing_address(stack_start),
+            size,
+            flags,
+            next: 0,
+        }
+    }
+
+    /// The size of the virtual memory area for `count` stacks of `size` pages, including the
+    /// guard pages, or `None` if it overflows.
+    pub(crate) fn area_size(size: u64, count: u64) -> Option<u64> {
+        size.checked_add(1)?
+            .checked_mul(Size4KiB::SIZE)?
+            .checked_mul(count)
+    }
+
+    /// The distance between the start addresses of two consecutive stacks.
+    pub(crate) fn stride(&self) -> u64 {
+        (self.size + 1) * Size4KiB::SIZE
+    }
+
+    /// Maps the next stack and returns its start address and top.
+    pub(crate) fn map_next(
+        &mut self,
+        page_table: &mut OffsetPageTable,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<(VirtAddr, VirtAddr), MapToError> {
+        let stack_start = self.stack_start + self.next * (self.size + 1);
+        let stack_end = stack_start + self.size;
+        if page_table.translate_page(stack_start - 1).is_some() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+
+        let frames = frame_allocator
+            .allocate_contiguous(self.size, Size4KiB::SIZE, MemoryRegionType::KernelStack)
             .ok_or(MapToError::FrameAllocationFailed)?;
-        page_table.map_to(page, frame, flags, frame_allocator)?.flush();
+        for (page, frame) in Page::range(stack_start, stack_end).zip(frames) {
+            page_table.map_to(page, frame, self.flags, frame_allocator)?;
+        }
+        self.next += 1;
+
+        Ok((stack_start.start_address(), stack_end.start_address()))
     }
+}
 
-    Ok(stack_end.start_address())
+/// Maps the physical address range `0..end` to the virtual address range `offset..offset + end`
+/// using pages of size `S`.
+///
+/// The end address is rounded up to the next page boundary, so that regions at the end of the
+/// physical address space (e.g. memory mapped devices) are fully accessible.
+pub(crate) fn map_physical_memory<S: PageSize>(
+    end: PhysAddr,
+    offset: u64,
+    flags: PageTableFlags,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<(), MapToError> {
+    let start_frame = PhysFrame::<S>::containing_address(PhysAddr::new(0));
+    let end_frame = PhysFrame::<S>::containing_address(end - 1u64);
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64() + offset));
+        page_table.map_to(page, frame, flags, frame_allocator)?;
+    }
+    Ok(())
 }
 
-pub(crate) fn map_segment(
+/// Maps a loadable segment directly from the kernel executable.
+///
+/// Pages in the `relro` range are never mapped as huge pages, so that they can be made
+/// read-only individually.
+fn map_segment(
     segment: &ProgramHeader64,
     kernel_start: PhysAddr,
-    page_table: &mut RecursivePageTable,
+    relro: Option<(VirtAddr, VirtAddr)>,
+    page_table: &mut OffsetPageTable,
     frame_allocator: &mut FrameAllocator,
 ) -> Result<(), MapToError> {
     let typ = segment.get_type().unwrap();
@@ -56,19 +168,12 @@ pub(crate) fn map_segment(
             let start_frame = PhysFrame::containing_address(phys_start_addr);
             let end_frame = PhysFrame::containing_address(phys_start_addr + file_size - 1u64);
 
-            let flags = segment.flags;
-            let mut page_table_flags = PageTableFlags::PRESENT;
-            if !flags.is_execute() {
-                page_table_flags |= PageTableFlags::NO_EXECUTE
-            };
-            if flags.is_write() {
-                page_table_flags |= PageTableFlags::WRITABLE
-            };
+            let page_table_flags = segment_flags(segment);
 
             for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
                 let offset = frame - start_frame;
                 let page = start_page + offset;
-                page_table.map_to(page, frame, page_table_flags, frame_allocator)?.flush();
+                page_table.map_to(page, frame, page_table_flags, frame_allocator)?;
             }
 
             if mem_size > file_size {
@@ -79,29 +184,21 @@ pub(crate) fn map_segment(
                     // A part of the last mapped frame needs to be zeroed. This is
                     // not possible since it could already contains parts of the next
                     // segment. Thus, we need to copy it before zeroing.
-
-                    // TODO: search for a free page dynamically
-                    let temp_page: Page = Page::containing_address(VirtAddr::new(0xfeeefeee000));
                     let new_frame = frame_allocator
                         .allocate_frame(MemoryRegionType::Kernel)
                         .ok_or(MapToError::FrameAllocationFailed)?;
-                    page_table.map_to(
-                        temp_page.clone(),
-                        new_frame.clone(),
-                        page_table_flags,
-                        frame_allocator,
-                    )?
-                    .flush();
 
                     type PageArray = [u64; Size4KiB::SIZE as usize / 8];
 
                     let last_page = Page::containing_address(virt_start_addr + file_size - 1u64);
                     let last_page_ptr = last_page.start_address().as_ptr::<PageArray>();
-                    let temp_page_ptr = temp_page.start_address().as_mut_ptr::<PageArray>();
+                    let new_frame_ptr = page_table
+                        .phys_to_virt(new_frame.start_address())
+                        .as_mut_ptr::<PageArray>();
 
                     unsafe {
                         // copy contents
-                        temp_page_ptr.write(last_page_ptr.read());
+                        new_frame_ptr.write(last_page_ptr.read());
                     }
 
                     // remap last page
@@ -118,8 +215,7 @@ pub(crate) fn map_segment(
                         new_frame,
                         page_table_flags,
                         frame_allocator,
-                    )?
-                    .flush();
+                    )?;
                 }
 
                 // Map additional frames.
@@ -127,13 +223,15 @@ pub(crate) fn map_segment(
                     zero_start.as_u64(),
                     Size4KiB::SIZE,
                 )));
-                let end_page = Page::containing_address(zero_end);
-                for page in Page::range_inclusive(start_page, end_page) {
-                    let frame = frame_allocator
-                        .allocate_frame(MemoryRegionType::Kernel)
-                        .ok_or(MapToError::FrameAllocationFailed)?;
-                    page_table.map_to(page, frame, page_table_flags, frame_allocator)?.flush();
-                }
+                let end_page = Page::containing_address(zero_end - 1u64);
+                map_new_frames(
+                    start_page,
+                    end_page,
+                    page_table_flags,
+                    relro,
+                    page_table,
+                    frame_allocator,
+                )?;
 
                 // zero
                 for offset in file_size..mem_size {
@@ -146,3 +244,460 @@ pub(crate) fn map_segment(
     }
     Ok(())
 }
+
+/// The reasons why [`copy_kernel`] can fail.
+#[derive(Debug)]
+pub(crate) enum CopyKernelError {
+    Map(MapToError),
+    SharedPageFlags(FlagUpdateError),
+}
+
+impl From<MapToError> for CopyKernelError {
+    fn from(err: MapToError) -> Self {
+        CopyKernelError::Map(err)
+    }
+}
+
+/// Maps the loadable kernel segments to newly allocated frames and copies their contents from
+/// the kernel executable, which lies in physical memory at `kernel_start`.
+///
+/// Unlike [`map_kernel`], this works for segments whose virtual address and file offset differ
+/// modulo the page size, and segments that share a page of the file don't share frames. The
+/// segments are written through their final mapping, so write protection must not be enabled
+/// yet.
+pub(crate) fn copy_kernel(
+    kernel_start: PhysAddr,
+    segments: Segments,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<(), CopyKernelError> {
+    let relro = relro_range(segments);
+    let mut previous = None;
+    for segment in segments.iter() {
+        if segment.get_type() != Ok(program::Type::Load) || segment.mem_size == 0 {
+            continue;
+        }
+        previous = Some(copy_segment(
+            &segment,
+            kernel_start,
+            previous,
+            relro,
+            page_table,
+            frame_allocator,
+        )?);
+    }
+    Ok(())
+}
+
+/// Returns the range of pages that are made read-only because of the `PT_GNU_RELRO` segment.
+///
+/// Like the dynamic linker, both ends of the segment are rounded down to page boundaries. The
+/// linker aligns the end of the segment, so that no other data shares its last page.
+fn relro_range(segments: Segments) -> Option<(VirtAddr, VirtAddr)> {
+    let relro = segments
+        .iter()
+        .find(|segment| segment.get_type() == Ok(program::Type::GnuRelro))?;
+    let start = align_down(relro.virtual_addr, Size4KiB::SIZE);
+    let end = align_down(relro.virtual_addr + relro.mem_size, Size4KiB::SIZE);
+    if start < end {
+        Some((VirtAddr::new(start), VirtAddr::new(end)))
+    } else {
+        None
+    }
+}
+
+/// Makes the pages covered by the `PT_GNU_RELRO` segment read-only.
+///
+/// The segment marks data that only needs to be writable while relocations are applied, so
+/// this must be called after the relocations of the kernel were applied.
+pub(crate) fn apply_relro(segments: Segments, page_table: &mut OffsetPageTable) {
+    let (start, end) = match relro_range(segments) {
+        Some(range) => range,
+        None => return,
+    };
+    let load_segment = segments
+        .iter()
+        .find(|segment| {
+            segment.get_type() == Ok(program::Type::Load)
+                && segment.virtual_addr <= start.as_u64()
+                && start.as_u64() < segment.virtual_addr + segment.mem_size
+        })
+        .expect("RELRO segment does not lie in a loadable segment");
+    let flags = segment_flags(&load_segment) - PageTableFlags::WRITABLE;
+
+    // The range is never mapped with huge pages, see `map_new_frames`.
+    let start_page: Page = Page::containing_address(start);
+    let end_page: Page = Page::containing_address(end - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        page_table
+            .update_flags(page, flags)
+            .unwrap_or_else(|err| {
+                panic!("failed to make RELRO page {:?} read-only: {:?}", page, err)
+            });
+    }
+}
+
+/// Maps a single segment to new frames and returns its last page and flags.
+///
+/// Loadable segments are sorted by their virtual address, so only the first page of a segment
+/// can be shared with the last page of the `previous` segment. Such a page is mapped with the
+/// permissions of both segments, which is reported on the screen if they differ.
+fn copy_segment(
+    segment: &ProgramHeader64,
+    kernel_start: PhysAddr,
+    previous: Option<(Page, PageTableFlags)>,
+    relro: Option<(VirtAddr, VirtAddr)>,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<(Page, PageTableFlags), CopyKernelError> {
+    let flags = segment_flags(segment);
+    let virt_start_addr = VirtAddr::new(segment.virtual_addr);
+    let start_page: Page = Page::containing_address(virt_start_addr);
+    let end_page: Page = Page::containing_address(virt_start_addr + segment.mem_size - 1u64);
+
+    let mut zero_start = start_page.start_address();
+    let mut fresh_start = start_page;
+    match previous {
+        Some((previous_page, previous_flags)) if previous_page == start_page => {
+            // The shared page needs the permissions of both segments. It already contains
+            // data of the previous segment, so only the part of this segment is zeroed.
+            let mut merged_flags = (flags | previous_flags) - PageTableFlags::NO_EXECUTE;
+            if flags.contains(PageTableFlags::NO_EXECUTE)
+                && previous_flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                merged_flags |= PageTableFlags::NO_EXECUTE;
+            }
+            if merged_flags != flags || merged_flags != previous_flags {
+                let _ = write!(
+                    crate::printer::Printer,
+                    "Segments share the page at {:#x}, mapping it as {:?}. ",
+                    start_page.start_address().as_u64(),
+                    merged_flags
+                );
+            }
+            page_table
+                .update_flags(start_page, merged_flags)
+                .map_err(CopyKernelError::SharedPageFlags)?;
+            zero_start = virt_start_addr;
+            fresh_start += 1;
+        }
+        _ => {}
+    }
+
+    if fresh_start <= end_page {
+        if fresh_start < end_page {
+            map_new_frames(
+                fresh_start,
+                end_page - 1,
+                flags,
+                relro,
+                page_table,
+                frame_allocator,
+            )?;
+        }
+        // The last page is never part of a huge page, so that it can be shared with the next
+        // segment.
+        let frame = frame_allocator
+            .allocate_frame(MemoryRegionType::Kernel)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        page_table.map_to(end_page, frame, flags, frame_allocator)?;
+    }
+
+    let zero_end = end_page.start_address() + Size4KiB::SIZE;
+    unsafe {
+        ptr::write_bytes(
+            zero_start.as_mut_ptr::<u8>(),
+            0,
+            usize_from(zero_end.as_u64() - zero_start.as_u64()),
+        );
+    }
+
+    // The kernel executable is no longer mapped, so it is read through the physical memory
+    // mapping of the bootloader.
+    let source = page_table.phys_to_virt(kernel_start + segment.offset);
+    unsafe {
+        ptr::copy_nonoverlapping(
+            source.as_ptr::<u8>(),
+            virt_start_addr.as_mut_ptr::<u8>(),
+            usize_from(segment.file_size),
+        );
+    }
+
+    Ok((end_page, flags))
+}
+
+/// Returns the page table flags for the permissions of the passed segment.
+fn segment_flags(segment: &ProgramHeader64) -> PageTableFlags {
+    let mut page_table_flags = PageTableFlags::PRESENT;
+    if !segment.flags.is_execute() {
+        page_table_flags |= PageTableFlags::NO_EXECUTE
+    };
+    if segment.flags.is_write() {
+        page_table_flags |= PageTableFlags::WRITABLE
+    };
+    page_table_flags
+}
+
+/// Maps the `count` frames starting at `start_frame` to the pages starting at `window`.
+pub(crate) fn map_window(
+    start_frame: PhysFrame,
+    count: u64,
+    window: Page,
+    flags: PageTableFlags,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<(), MapToError> {
+    let frames = PhysFrame::range(start_frame, start_frame + count);
+    for (page, frame) in Page::range(window, window + count).zip(frames) {
+        page_table.map_to(page, frame, flags, frame_allocator)?;
+    }
+    Ok(())
+}
+
+/// Maps the pages `start..=end` to newly allocated kernel frames.
+///
+/// Every 2MiB-aligned part of the range is mapped with a 2MiB page if a suitable
+/// contiguous frame is available, which saves both frames for page tables and TLB entries.
+/// Parts that overlap the `relro` range are always mapped with 4KiB pages.
+fn map_new_frames(
+    start: Page,
+    end: Page,
+    flags: PageTableFlags,
+    relro: Option<(VirtAddr, VirtAddr)>,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<(), MapToError> {
+    const PAGES_PER_HUGE_PAGE: u64 = Size2MiB::SIZE / Size4KiB::SIZE;
+
+    let mut page = start;
+    while page <= end {
+        if let Ok(huge_page) = Page::<Size2MiB>::from_start_address(page.start_address()) {
+            let huge_page_end = huge_page.start_address() + Size2MiB::SIZE;
+            let in_relro = relro.map_or(false, |(relro_start, relro_end)| {
+                huge_page.start_address() < relro_end && relro_start < huge_page_end
+            });
+            if end - page >= PAGES_PER_HUGE_PAGE - 1 && !in_relro {
+                if let Some(frame) =
+                    frame_allocator.allocate_large_frame::<Size2MiB>(MemoryRegionType::Kernel)
+                {
+                    page_table.map_to(huge_page, frame, flags, frame_allocator)?;
+                    page += PAGES_PER_HUGE_PAGE;
+                    continue;
+                }
+            }
+        }
+
+        let frame = frame_allocator
+            .allocate_frame(MemoryRegionType::Kernel)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        page_table.map_to(page, frame, flags, frame_allocator)?;
+        page += 1;
+    }
+    Ok(())
+}
+
+/// Calls `report` for every virtual address range that is mapped both writable and executable.
+///
+/// Adjacent ranges are merged. The physical memory mapping of the bootloader is skipped.
+pub(crate) fn find_writable_executable<F>(page_table: &mut OffsetPageTable, mut report: F)
+where
+    F: FnMut(VirtAddr, VirtAddr),
+{
+    let mut current: Option<(u64, u64)> = None;
+    visit_mappings(page_table, |page, size, flags, _| {
+        if !flags.contains(PageTableFlags::WRITABLE) || flags.contains(PageTableFlags::NO_EXECUTE) {
+            return;
+        }
+        let start = page.start_address().as_u64();
+        match current {
+            Some((_, ref mut end)) if *end == start => *end += size,
+            _ => {
+                if let Some((start, end)) = current {
+                    report(VirtAddr::new(start), VirtAddr::new(end));
+                }
+                current = Some((start, start + size));
+            }
+        }
+    });
+    if let Some((start, end)) = current {
+        report(VirtAddr::new(start), VirtAddr::new(end));
+    }
+}
+
+/// Removes the level 2 tables of the lower 4GiB identity mapping of stage 3 that are empty.
+///
+/// Stage 3 maps the kernel executable through up to four level 2 tables, which lie in the
+/// bootloader memory. Once the executable is unmapped, they must not be reused for the mappings
+/// of the kernel.
+pub(crate) fn remove_empty_identity_tables(page_table: &mut OffsetPageTable) {
+    let p4 = page_table.level_4_table();
+    let p3 = page_table.table(p4[0].addr());
+    for entry in p3.iter_mut().skip(1).take(3) {
+        if !entry.is_unused() && page_table.table(entry.addr()).iter().all(|e| e.is_unused()) {
+            entry.set_unused();
+        }
+    }
+    tlb::flush_all();
+}
+
+/// Creates the level 4 page table for the kernel, which contains all current mappings except
+/// for the bootloader region, i.e. the lower 2MiB of the address space, and the physical memory
+/// mapping of the bootloader.
+///
+/// Stage 3 maps the bootloader region through the first entries of its level 4, level 3, and
+/// level 2 tables, which lie in the bootloader memory themselves. These three tables are copied
+/// to newly allocated frames (or left out if they map nothing else), while all other page
+/// tables are shared with the current hierarchy. The new table is recursively mapped at
+/// `recursive_index` if passed.
+pub(crate) fn create_kernel_page_table(
+    recursive_index: Option<u9>,
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<PhysFrame, MapToError> {
+    fn maps_only_first(table: &PageTable) -> bool {
+        (1..512).all(|i| table[i].is_unused())
+    }
+
+    let offset_indices = page_table.offset_indices();
+    let p4 = page_table.level_4_table();
+    let p3 = page_table.table(p4[0].addr());
+    let p2 = page_table.table(p3[0].addr());
+
+    let new_p2 = if maps_only_first(p2) {
+        None
+    } else {
+        Some(copy_table(p2, None, page_table, frame_allocator)?)
+    };
+    let new_p3 = if new_p2.is_none() && maps_only_first(p3) {
+        None
+    } else {
+        Some(copy_table(p3, new_p2, page_table, frame_allocator)?)
+    };
+    let new_p4 = copy_table(p4, new_p3, page_table, frame_allocator)?;
+
+    let new_p4_table = page_table.table(new_p4.start_address());
+    for index in offset_indices {
+        new_p4_table[index].set_unused();
+    }
+    if let Some(index) = recursive_index {
+        let entry = &mut new_p4_table[usize::from(u16::from(index))];
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        entry.set_addr(new_p4.start_address(), flags);
+    }
+    Ok(new_p4)
+}
+
+/// Copies the page table `source` to a newly allocated frame and points its first entry to
+/// `first` instead, or leaves it unused if `first` is `None`.
+fn copy_table(
+    source: &PageTable,
+    first: Option<PhysFrame>,
+    page_table: &OffsetPageTable,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<PhysFrame, MapToError> {
+    let frame = frame_allocator
+        .allocate_frame(MemoryRegionType::PageTable)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let table = page_table.table(frame.start_address());
+    for i in 1..512 {
+        if source[i].is_unused() {
+            table[i].set_unused();
+        } else {
+            table[i].set_addr(source[i].addr(), source[i].flags());
+        }
+    }
+    match first {
+        Some(first) => table[0].set_addr(first.start_address(), source[0].flags()),
+        None => table[0].set_unused(),
+    }
+    Ok(frame)
+}
+
+/// Sets the `GLOBAL` flag on all mappings in the higher half of the address space.
+///
+/// Global mappings are not flushed from the TLB when `CR3` is reloaded, so kernels that share
+/// their higher half between all address spaces keep their TLB entries on address space
+/// switches. Stage 3 enables global pages in `CR4`.
+pub(crate) fn make_higher_half_global(page_table: &mut OffsetPageTable) {
+    visit_mappings(page_table, |page, _, _, entry| {
+        if page.start_address().as_u64() >= 0xffff_8000_0000_0000 {
+            let flags = entry.flags() | PageTableFlags::GLOBAL;
+            entry.set_addr(entry.addr(), flags);
+        }
+    });
+}
+
+/// Calls `visit` for every mapped page (including huge pages) with its size, its effective
+/// permissions, and its page table entry.
+///
+/// The physical memory mapping of the bootloader is skipped. The effective permissions only
+/// contain `WRITABLE` if all levels allow writes, and contain `NO_EXECUTE` if any level forbids
+/// execution.
+fn visit_mappings<F>(page_table: &mut OffsetPageTable, mut visit: F)
+where
+    F: FnMut(Page, u64, PageTableFlags, &mut PageTableEntry),
+{
+    fn index(i: usize) -> u9 {
+        u9::new(i as u16)
+    }
+    fn combine(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if parent.contains(PageTableFlags::WRITABLE) && entry.contains(PageTableFlags::WRITABLE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if parent.contains(PageTableFlags::NO_EXECUTE) || entry.contains(PageTableFlags::NO_EXECUTE)
+        {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    let zero = u9::new(0);
+    let mapped = |flags: PageTableFlags| flags.contains(PageTableFlags::PRESENT);
+    let huge = |flags: PageTableFlags| flags.contains(PageTableFlags::HUGE_PAGE);
+    let offset_indices = page_table.offset_indices();
+    let p4 = page_table.level_4_table();
+    for i in 0..512 {
+        if offset_indices.contains(&i) || !mapped(p4[i].flags()) {
+            continue;
+        }
+        let p4_flags = combine(PageTableFlags::WRITABLE, p4[i].flags());
+        let p3 = page_table.table(p4[i].addr());
+        for j in 0..512 {
+            if !mapped(p3[j].flags()) {
+                continue;
+            }
+            let p3_flags = combine(p4_flags, p3[j].flags());
+            if huge(p3[j].flags()) {
+                let page = Page::from_page_table_indices(index(i), index(j), zero, zero);
+                visit(page, Size1GiB::SIZE, p3_flags, &mut p3[j]);
+                continue;
+            }
+            let p2 = page_table.table(p3[j].addr());
+            for k in 0..512 {
+                if !mapped(p2[k].flags()) {
+                    continue;
+                }
+                let p2_flags = combine(p3_flags, p2[k].flags());
+                if huge(p2[k].flags()) {
+                    let page = Page::from_page_table_indices(index(i), index(j), index(k), zero);
+                    visit(page, Size2MiB::SIZE, p2_flags, &mut p2[k]);
+                    continue;
+                }
+                let p1 = page_table.table(p2[k].addr());
+                for l in 0..512 {
+                    if !mapped(p1[l].flags()) {
+                        continue;
+                    }
+                    let p1_flags = combine(p2_flags, p1[l].flags());
+                    let page =
+                        Page::from_page_table_indices(index(i), index(j), index(k), index(l));
+                    visit(page, Size4KiB::SIZE, p1_flags, &mut p1[l]);
+                }
+            }
+        }
+    }
+}
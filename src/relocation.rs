@@ -0,0 +1,90 @@
+use crate::segments::Segments;
+use core::{mem, slice};
+use usize_conversions::usize_from;
+use xmas_elf::dynamic::{Dynamic, Tag};
+use xmas_elf::program;
+use xmas_elf::sections::Rela;
+use xmas_elf::P64;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Applies the dynamic relocations of a position independent kernel that was loaded at
+/// `load_base`.
+///
+/// The relocation table is read through the virtual mapping of the kernel and relocations are
+/// written through it, so this must be called after the kernel segments are mapped, but before
+/// write protection is enabled. The virtual addresses of the passed segments must already
+/// include the load base.
+pub(crate) fn apply(segments: Segments, load_base: u64) {
+    let dynamic_segment = segments
+        .iter()
+        .find(|segment| segment.get_type() == Ok(program::Type::Dynamic));
+    let dynamic_segment = match dynamic_segment {
+        Some(segment) => segment,
+        None => return,
+    };
+
+    let entries = unsafe {
+        slice::from_raw_parts(
+            dynamic_segment.virtual_addr as *const Dynamic<P64>,
+            usize_from(dynamic_segment.mem_size) / mem::size_of::<Dynamic<P64>>(),
+        )
+    };
+    let mut rela = None;
+    let mut rela_size = None;
+    let mut rela_entry_size = None;
+    for entry in entries {
+        match entry.get_tag() {
+            Ok(Tag::Rela) => rela = entry.get_ptr().ok(),
+            Ok(Tag::RelaSize) => rela_size = entry.get_val().ok(),
+            Ok(Tag::RelaEnt) => rela_entry_size = entry.get_val().ok(),
+            Ok(Tag::Null) => break,
+            _ => {}
+        }
+    }
+    let (rela, rela_size) = match (rela, rela_size) {
+        (Some(rela), Some(size)) => (rela, size),
+        (None, None) => return,
+        _ => panic!("incomplete relocation information in dynamic segment"),
+    };
+    let entry_size = mem::size_of::<Rela<P64>>() as u64;
+    assert!(
+        rela_entry_size.unwrap_or(entry_size) == entry_size,
+        "unsupported relocation entry size {:?}",
+        rela_entry_size
+    );
+
+    let relocations = unsafe {
+        slice::from_raw_parts(
+            (load_base + rela) as *const Rela<P64>,
+            usize_from(rela_size / entry_size),
+        )
+    };
+    for relocation in relocations {
+        match relocation.get_type() {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let addr = load_base + relocation.get_offset();
+                check_in_segment(segments, addr);
+                let value = load_base + relocation.get_addend();
+                unsafe { (addr as *mut u64).write_unaligned(value) };
+            }
+            other => panic!("unsupported relocation type {}", other),
+        }
+    }
+}
+
+/// Panics if the 8 bytes at `addr` are not part of a loadable kernel segment.
+fn check_in_segment(segments: Segments, addr: u64) {
+    let in_segment = segments.iter().any(|segment| {
+        segment.get_type() == Ok(program::Type::Load)
+            && addr >= segment.virtual_addr
+            && addr + 8 <= segment.virtual_addr + segment.mem_size
+    });
+    assert!(
+        in_segment,
+        "relocation target {:#x} lies outside of the kernel segments",
+        addr
+    );
+}
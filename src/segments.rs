@@ -0,0 +1,69 @@
+use crate::offset_page_table::OffsetPageTable;
+use core::{mem, slice};
+use usize_conversions::usize_from;
+use x86_64::PhysAddr;
+use xmas_elf::program::ProgramHeader64;
+use xmas_elf::ElfFile;
+
+/// The program headers of the kernel.
+///
+/// The headers are read directly from the program header table of the kernel executable, so
+/// that any number of segments is supported. The virtual addresses of the returned headers
+/// include the load base of the kernel.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Segments {
+    table: *const ProgramHeader64,
+    count: usize,
+    load_base: u64,
+}
+
+impl Segments {
+    /// Creates a view of the program header table of `elf_file`.
+    ///
+    /// The ELF file must stay mapped until the table is moved to another virtual address
+    /// with [`Segments::move_table`]. The ELF class must already be checked.
+    pub(crate) fn new(elf_file: &ElfFile, load_base: u64) -> Self {
+        let header = &elf_file.header.pt2;
+        let entry_size = usize::from(header.ph_entry_size());
+        assert!(
+            entry_size == mem::size_of::<ProgramHeader64>(),
+            "unsupported program header size {}",
+            entry_size
+        );
+        let count = usize::from(header.ph_count());
+        let offset = usize_from(header.ph_offset());
+        let end = offset + count * entry_size;
+        assert!(end <= elf_file.input.len(), "program header table is out of bounds");
+
+        let table = elf_file.input[offset..end].as_ptr() as *const ProgramHeader64;
+        assert!(
+            table as usize % mem::align_of::<ProgramHeader64>() == 0,
+            "program header table is not aligned"
+        );
+        Segments {
+            table,
+            count,
+            load_base,
+        }
+    }
+
+    /// Returns the program headers with the load base applied to their virtual addresses.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = ProgramHeader64> + '_ {
+        let load_base = self.load_base;
+        let headers = unsafe { slice::from_raw_parts(self.table, self.count) };
+        headers.iter().map(move |header| ProgramHeader64 {
+            virtual_addr: load_base + header.virtual_addr,
+            ..*header
+        })
+    }
+
+    /// Reads the program header table through the physical memory mapping of the bootloader
+    /// afterwards.
+    ///
+    /// The ELF file must still be identity mapped. Moving the table allows to unmap the ELF
+    /// file, which might collide with the kernel segments.
+    pub(crate) fn move_table(&mut self, page_table: &OffsetPageTable) {
+        let table_addr = PhysAddr::new(self.table as u64);
+        self.table = page_table.phys_to_virt(table_addr).as_ptr();
+    }
+}
@@ -3,8 +3,8 @@
 .intel_syntax noprefix
 .code16
 
-# This stage initializes the stack, enables the A20 line, loads the rest of
-# the bootloader from disk, and jumps to stage_2.
+# This stage initializes the stack, loads the rest of the bootloader from disk,
+# and jumps to stage_2.
 
 _start:
     # zero segment registers
@@ -22,60 +22,38 @@ _start:
     # initialize stack
     mov sp, 0x7c00
 
+    # the BIOS passes the boot drive in dl, which later stages need for disk reads
+    mov [boot_drive], dl
+
     lea si, boot_start_str
     call real_mode_println
 
-enable_a20:
-    # enable A20-Line via IO-Port 92, might not work on all motherboards
-    in al, 0x92
-    test al, 2
-    jnz enable_a20_after
-    or al, 2
-    and al, 0xFE
-    out 0x92, al
-enable_a20_after:
-
-
-enter_protected_mode:
-    # clear interrupts
-    cli
-    push ds
-    push es
-
-    lgdt [gdt32info]
-
-    mov eax, cr0
-    or al, 1    # set protected mode bit
-    mov cr0, eax
-
-    jmp protected_mode                # tell 386/486 to not crash
-
-protected_mode:
-    mov bx, 0x10
-    mov ds, bx # set data segment
-    mov es, bx # set extra segment
-
-    and al, 0xfe    # clear protected mode bit
-    mov cr0, eax
-
-unreal_mode:
-    pop es # get back old extra segment
-    pop ds # get back old data segment
-    sti
-
-    # back to real mode, but internal data segment register is still loaded
-    # with gdt segment -> we can access the full 4GiB of memory
-
-    mov bx, 0x0f01         # attrib/char of smiley
-    mov eax, 0xb8f00       # note 32 bit offset
-    mov word ptr ds:[eax], bx
-
 check_int13h_extensions:
     mov ah, 0x41
     mov bx, 0x55aa
     # dl contains drive number
     int 0x13
-    jc no_int13h_extensions
+    jc query_disk_geometry
+    cmp bx, 0xaa55
+    jne query_disk_geometry
+    test cl, 1 # support for the disk access packet functions
+    jnz load_rest_of_bootloader_from_disk
+
+query_disk_geometry:
+    # fall back to CHS addressing
+    mov ah, 0x08
+    xor di, di # es:di = 0:0 to work around BIOS bugs
+    mov es, di
+    # the BIOS points es:di to the diskette parameter table for floppy drives
+    push es
+    int 0x13
+    pop es
+    jc disk_geometry_failed
+    and cl, 0x3f
+    mov [sectors_per_track], cl
+    movzx eax, dh
+    inc eax
+    mov [head_count], eax
 
 load_rest_of_bootloader_from_disk:
     lea eax, _rest_of_bootloader_start_addr
@@ -95,9 +73,7 @@ load_rest_of_bootloader_from_disk:
     shr eax, 9 # divide by 512 (block size)
     mov [dap_start_lba], eax
 
-    lea si, dap
-    mov ah, 0x42
-    int 0x13
+    call read_disk
     jc rest_of_bootloader_load_failed
 
 jump_to_second_stage:
@@ -148,74 +124,98 @@ real_mode_print_char:
     int 0x10
     ret
 
-# print a number in hex
-# IN
-#   bx: the number
+# read the blocks described by the disk access packet from the boot drive,
+# using CHS addressing if the int13h extensions are not supported
+#
+# Failed reads are retried after resetting the disk system. The BIOS status
+# code of the last attempt is stored in disk_status.
+# OUT
+#   carry flag: set on failure
 # CLOBBER
-#   al, cx
-real_mode_print_hex:
-    mov cx, 4
-.lp:
-    mov al, bh
-    shr al, 4
-
-    cmp al, 0xA
-    jb .below_0xA
-
-    add al, 'A' - 0xA - '0'
-.below_0xA:
-    add al, '0'
-
-    call real_mode_print_char
-
-    shl bx, 4
-    loop .lp
+#   ax, dl, si
+read_disk:
+    push cx
+    mov cx, 3 # number of attempts
+read_disk_attempt:
+    # the BIOS reports the number of blocks read on failure
+    push word ptr [dap_blocks]
+    call read_disk_once
+    pop word ptr [dap_blocks]
+    jnc read_disk_done
+    xor ax, ax # reset disk system
+    int 0x13
+    loop read_disk_attempt
+    stc
+read_disk_done:
+    pop cx
+    ret
 
+read_disk_once:
+    mov dl, [boot_drive]
+    cmp byte ptr [sectors_per_track], 0
+    jne read_disk_chs
+    lea si, dap
+    mov ah, 0x42
+    int 0x13
+    mov [disk_status], ah
+    ret
+read_disk_chs:
+    pushad
+    push es
+    mov eax, [dap_start_lba]
+    les bx, [dap_buffer_addr]
+    mov di, [dap_blocks]
+read_disk_chs_block:
+    pushad
+    # sector = lba % sectors_per_track + 1
+    xor edx, edx
+    div dword ptr [sectors_per_track]
+    mov cl, dl
+    inc cl
+    # head = track % head_count, cylinder = track / head_count
+    xor edx, edx
+    div dword ptr [head_count]
+    mov dh, dl
+    mov ch, al
+    shl ah, 6
+    or cl, ah # bits 8 and 9 of the cylinder
+    mov dl, [boot_drive]
+    mov ax, 0x0201 # read one sector
+    int 0x13
+    mov [disk_status], ah
+    popad
+    jc read_disk_chs_done
+    # next block
+    inc eax
+    mov si, es
+    add si, 512 / 16
+    mov es, si
+    dec di
+    jnz read_disk_chs_block
+read_disk_chs_done:
+    pop es
+    popad
     ret
 
-real_mode_error:
+disk_geometry_failed:
+    lea si, disk_geometry_failed_str
     call real_mode_println
     jmp spin
 
-no_int13h_extensions:
-    lea si, no_int13h_extensions_str
-    jmp real_mode_error
-
 rest_of_bootloader_load_failed:
     lea si, rest_of_bootloader_load_failed_str
-    jmp real_mode_error
+    call real_mode_println
+    jmp spin
 
 boot_start_str: .asciz "Booting (first stage)..."
-error_str: .asciz "Error: "
-no_int13h_extensions_str: .asciz "No support for int13h extensions"
+disk_geometry_failed_str: .asciz "Failed to query disk geometry"
 rest_of_bootloader_load_failed_str: .asciz "Failed to load rest of bootloader"
 
-gdt32info:
-   .word gdt32_end - gdt32 - 1  # last byte in table
-   .word gdt32                  # start of table
-
-gdt32:
-    # entry 0 is always unused
-    .quad 0
-codedesc:
-    .byte 0xff
-    .byte 0xff
-    .byte 0
-    .byte 0
-    .byte 0
-    .byte 0x9a
-    .byte 0xcf
-    .byte 0
-datadesc:
-    .byte 0xff
-    .byte 0xff
-    .byte 0
-    .byte 0
-    .byte 0
-    .byte 0x92
-    .byte 0xcf
-    .byte 0
-gdt32_end:
+boot_drive: .byte 0
+disk_status: .byte 0
+# disk geometry for CHS addressing, zero if the int13h extensions are supported
+sectors_per_track: .long 0
+head_count: .long 0
 
 dap: # disk access packet
     .byte 0x10 # size of dap
@@ -2,23 +2,99 @@
 .intel_syntax noprefix
 .code16
 
-# This stage sets the target operating mode, loads the kernel from disk,
-# creates an e820 memory map, enters protected mode, and jumps to the
-# third stage.
+# This stage enables the A20 line and unreal mode, sets the target operating
+# mode, creates an e820 memory map, loads the kernel from disk to a usable
+# memory region, enters protected mode, and jumps to the third stage.
+
+# The number of blocks that are read from disk at once. Some BIOSes don't support
+# reading more than 127 blocks per call, which also fits into the 64KiB buffer.
+.set KERNEL_BUFFER_BLOCKS, 127
 
 second_stage_start_str: .asciz "Booting (second stage)..."
+kernel_load_progress_str: .asciz "Loading kernel... "
 kernel_load_failed_str: .asciz "Failed to load kernel from disk"
+no_kernel_memory_str: .asciz "No usable memory region for the kernel"
+empty_kernel_str: .asciz "Kernel is empty"
+disk_status_str: .asciz ": status "
+disk_block_str: .asciz ", from "
+
+# The physical address of the kernel, chosen from the e820 memory map.
+kernel_load_addr: .quad 0
+# The size of the kernel in disk blocks.
+kernel_block_count: .long 0
 
 kernel_load_failed:
+    # end the line of the progress output
+    mov al, 13 # \r
+    call real_mode_print_char
+    mov al, 10 # \n
+    call real_mode_print_char
     lea si, [kernel_load_failed_str]
-    call real_mode_println
+    call real_mode_print
+    call real_mode_print_disk_error
 kernel_load_failed_spin:
     jmp kernel_load_failed_spin
 
+no_kernel_memory:
+    lea si, [no_kernel_memory_str]
+    call real_mode_println
+no_kernel_memory_spin:
+    jmp no_kernel_memory_spin
+
+empty_kernel:
+    lea si, [empty_kernel_str]
+    call real_mode_println
+empty_kernel_spin:
+    jmp empty_kernel_spin
+
 stage_2:
     lea si, [second_stage_start_str]
     call real_mode_println
 
+enable_a20:
+    # enable A20-Line via IO-Port 92, might not work on all motherboards
+    in al, 0x92
+    test al, 2
+    jnz enable_a20_after
+    or al, 2
+    and al, 0xFE
+    out 0x92, al
+enable_a20_after:
+
+enter_protected_mode:
+    # clear interrupts
+    cli
+    push ds
+    push es
+
+    lgdt [gdt32info]
+
+    mov eax, cr0
+    or al, 1    # set protected mode bit
+    mov cr0, eax
+
+    jmp protected_mode                # tell 386/486 to not crash
+
+protected_mode:
+    mov bx, 0x10
+    mov ds, bx # set data segment
+    mov es, bx # set extra segment
+
+    and al, 0xfe    # clear protected mode bit
+    mov cr0, eax
+
+unreal_mode:
+    pop es # get back old extra segment
+    pop ds # get back old data segment
+    sti
+
+    # back to real mode, but internal data segment register is still loaded
+    # with gdt segment -> we can access the full 4GiB of memory
+
+    mov bx, 0x0f01         # attrib/char of smiley
+    mov eax, 0xb8f00       # note 32 bit offset
+    mov word ptr ds:[eax], bx
+
 set_target_operating_mode:
     # Some BIOSs assume the processor will only operate in Legacy Mode. We change the Target
     # Operating Mode to "Long Mode Target Only", so the firmware expects each CPU to enter Long Mode
@@ -31,13 +107,28 @@ set_target_operating_mode:
     int 0x15
     popf
 
-load_kernel_from_disk:
-    # start of memory buffer
-    lea eax, _kernel_buffer
-    mov [dap_buffer_addr], ax
+create_memory_map:
+    lea di, es:[_memory_map]
+    call do_e820
+
+check_kernel_size:
+    # the loading progress is computed relative to the kernel size
+    cmp dword ptr [_kib_kernel_size], 0
+    je empty_kernel
+
+choose_kernel_load_addr:
+    mov ecx, _kib_kernel_size
+    call find_kernel_load_addr
+    test eax, eax
+    jz no_kernel_memory
+    mov [kernel_load_addr], eax
 
-    # number of disk blocks to load
-    mov word ptr [dap_blocks], 1
+load_kernel_from_disk:
+    # start of memory buffer (the buffer is 64KiB aligned, so the offset is zero)
+    mov eax, offset _kernel_buffer
+    shr eax, 4
+    mov [dap_buffer_seg], ax
+    mov word ptr [dap_buffer_addr], 0
 
     # number of start block
     lea eax, _kernel_start_addr
@@ -47,44 +138,51 @@ load_kernel_from_disk:
     mov [dap_start_lba], eax
 
     # destination address
-    mov edi, 0x400000
+    mov edi, [kernel_load_addr]
 
     # block count
     mov ecx, _kib_kernel_size
     add ecx, 511 # align up
     shr ecx, 9
+    mov [kernel_block_count], ecx
 
-load_next_kernel_block_from_disk:
-    # load block from disk
-    lea si, dap
-    mov ah, 0x42
-    int 0x13
+load_next_kernel_blocks_from_disk:
+    # load as many blocks as the BIOS and the buffer allow
+    mov ebx, ecx
+    cmp ebx, KERNEL_BUFFER_BLOCKS
+    jbe load_kernel_blocks
+    mov ebx, KERNEL_BUFFER_BLOCKS
+load_kernel_blocks:
+    mov [dap_blocks], bx
+    call read_disk
     jc kernel_load_failed
 
-    # copy block to 2MiB
+    # copy blocks to the load address
     push ecx
     push esi
-    mov ecx, 512 / 4
-    # move with zero extension
-    # because we are moving a word ptr
-    # to esi, a 32-bit register.
-    movzx esi, word ptr [dap_buffer_addr]
+    mov ecx, ebx
+    shl ecx, 9 - 2 # number of dwords
+    mov esi, offset _kernel_buffer
     # move from esi to edi ecx times.
     rep movsd [edi], [esi]
     pop esi
     pop ecx
 
-    # next block
-    mov eax, [dap_start_lba]
-    add eax, 1
-    mov [dap_start_lba], eax
+    # next blocks
+    add [dap_start_lba], ebx
+    sub ecx, ebx
 
-    sub ecx, 1
-    jnz load_next_kernel_block_from_disk
+    pushad
+    call print_kernel_load_progress
+    popad
 
-create_memory_map:
-    lea di, es:[_memory_map]
-    call do_e820
+    test ecx, ecx
+    jnz load_next_kernel_blocks_from_disk
+
+    mov al, 13 # \r
+    call real_mode_print_char
+    mov al, 10 # \n
+    call real_mode_print_char
 
 video_mode_config:
     call config_video_mode
@@ -103,3 +201,172 @@ enter_protected_mode_again:
 
 spin32:
     jmp spin32
+
+# Prints the percentage of the kernel that is loaded, overwriting the current
+# line.
+# IN
+#   ecx: number of blocks that are not loaded yet
+# CLOBBER
+#   eax, ebx, ecx, edx, si
+print_kernel_load_progress:
+    mov eax, [kernel_block_count]
+    sub eax, ecx
+    mov ebx, 100
+    mul ebx
+    div dword ptr [kernel_block_count]
+    push ax
+    mov al, 13 # \r
+    call real_mode_print_char
+    lea si, [kernel_load_progress_str]
+    call real_mode_print
+    pop ax
+    call real_mode_print_decimal
+    mov al, '%'
+    jmp real_mode_print_char
+
+# print a number in decimal
+# IN
+#   ax: the number
+# CLOBBER
+#   ax, bx, cx, dx
+real_mode_print_decimal:
+    mov bx, 10
+    xor cx, cx
+real_mode_print_decimal_divide:
+    xor dx, dx
+    div bx
+    push dx
+    add cx, 1
+    test ax, ax
+    jnz real_mode_print_decimal_divide
+real_mode_print_decimal_digit:
+    pop ax
+    add al, '0'
+    call real_mode_print_char
+    loop real_mode_print_decimal_digit
+    ret
+
+# print a number in hex
+# IN
+#   bx: the number
+# CLOBBER
+#   al, cx
+real_mode_print_hex:
+    mov cx, 4
+real_mode_print_hex_digit:
+    mov al, bh
+    shr al, 4
+    cmp al, 0xA
+    jb real_mode_print_hex_below_0xA
+    add al, 'A' - 0xA - '0'
+real_mode_print_hex_below_0xA:
+    add al, '0'
+    call real_mode_print_char
+    shl bx, 4
+    loop real_mode_print_hex_digit
+    ret
+
+# print the BIOS status code and the start block of the failed disk read
+# CLOBBER
+#   ax, bx, cx, si
+real_mode_print_disk_error:
+    lea si, [disk_status_str]
+    call real_mode_print
+    movzx bx, byte ptr [disk_status]
+    call real_mode_print_hex
+    lea si, [disk_block_str]
+    call real_mode_print
+    mov bx, [dap_start_lba + 2]
+    call real_mode_print_hex
+    mov bx, [dap_start_lba]
+    jmp real_mode_print_hex
+
+# Chooses the physical address of the kernel from the e820 memory map.
+#
+# The kernel is placed at the lowest 2MiB aligned address above 4MiB in the
+# first usable region that is large enough, so that stage 3 can identity map it
+# with 2MiB pages. It must lie below 4GiB, as it is copied in unreal mode.
+# IN
+#   ecx: size of the kernel in bytes
+# OUT
+#   eax: load address, or 0 if no region is large enough
+# CLOBBER
+#   ebx, ecx, edx, si, di
+find_kernel_load_addr:
+    # the kernel is loaded in whole blocks, so round its size up to 512 bytes
+    add ecx, 512 - 1
+    jc find_kernel_load_addr_failed
+    and ecx, 0xfffffe00
+
+    lea si, [_memory_map]
+    mov di, [mmap_ent]
+find_kernel_load_addr_check_region:
+    test di, di
+    jz find_kernel_load_addr_failed
+    cmp dword ptr [si + 16], 1 # usable memory
+    jne find_kernel_load_addr_next_region
+    cmp dword ptr [si + 4], 0 # region starts above 4GiB
+    jne find_kernel_load_addr_next_region
+
+    # end of the region, limited to 4GiB
+    mov ebx, [si]
+    add ebx, [si + 8]
+    jc find_kernel_load_addr_limit_end
+    cmp dword ptr [si + 12], 0
+    je find_kernel_load_addr_start
+find_kernel_load_addr_limit_end:
+    mov ebx, 0xffffffff
+
+find_kernel_load_addr_start:
+    # start of the region, at least 4MiB and aligned up to 2MiB
+    mov eax, [si]
+    cmp eax, 0x400000
+    jae find_kernel_load_addr_align_start
+    mov eax, 0x400000
+find_kernel_load_addr_align_start:
+    add eax, 0x200000 - 1
+    jc find_kernel_load_addr_next_region
+    and eax, 0xffe00000
+
+    # check that the kernel fits
+    mov edx, eax
+    add edx, ecx
+    jc find_kernel_load_addr_next_region
+    cmp edx, ebx
+    ja find_kernel_load_addr_next_region
+    ret
+
+find_kernel_load_addr_next_region:
+    add si, 24 # size of an e820 entry
+    sub di, 1
+    jmp find_kernel_load_addr_check_region
+find_kernel_load_addr_failed:
+    xor eax, eax
+    ret
+
+gdt32info:
+   .word gdt32_end - gdt32 - 1  # last byte in table
+   .word gdt32                  # start of table
+
+gdt32:
+    # entry 0 is always unused
+    .quad 0
+codedesc:
+    .byte 0xff
+    .byte 0xff
+    .byte 0
+    .byte 0
+    .byte 0
+    .byte 0x9a
+    .byte 0xcf
+    .byte 0
+datadesc:
+    .byte 0xff
+    .byte 0xff
+    .byte 0
+    .byte 0
+    .byte 0
+    .byte 0x92
+    .byte 0xcf
+    .byte 0
+gdt32_end:
@@ -4,7 +4,7 @@
 
 # This stage performs some checks on the CPU (cpuid, long mode), sets up an
 # initial page table mapping (identity map the bootloader, map the P4
-# recursively, map the kernel blob to 4MB), enables paging, switches to long
+# recursively, identity map the kernel blob), enables paging, switches to long
 # mode, and jumps to stage_4.
 
 stage_3:
@@ -38,30 +38,38 @@ set_up_page_tables:
     # p4
     lea eax, [_p4]
     or eax, (1 | 2)
-    mov [_p4 + 511 * 8], eax # recursive mapping
+    mov [_p4 + 511 * 8], eax # recursive mapping, removed before the kernel is mapped
     lea eax, [_p3]
     or eax, (1 | 2)
     mov [_p4], eax
-    # p3
+    # p3 (the four consecutive p2 tables cover the lower 4GiB)
     lea eax, [_p2]
     or eax, (1 | 2)
-    mov [_p3], eax
+    mov ecx, 0
+    map_p3_table:
+    mov [_p3 + ecx * 8], eax
+    add eax, 4096
+    add ecx, 1
+    cmp ecx, 4
+    jb map_p3_table
     # p2
     lea eax, [_p1]
     or eax, (1 | 2)
     mov [_p2], eax
-    mov eax, (0x400000 | 1 | 2 | (1 << 7))
-    mov ecx, 2
-    mov edx, _kib_kernel_size
-    add edx, 0x400000 # start address
-    add edx, 0x200000 - 1 # align up
-    shr edx, 12 + 9 # end huge page number
+    mov eax, [kernel_load_addr] # 2MiB aligned by stage 2
+    mov ecx, eax
+    shr ecx, 12 + 9 # start huge page number
+    or eax, (1 | 2 | (1 << 7))
+    mov edx, [kernel_load_addr]
+    add edx, _kib_kernel_size
+    sub edx, 1
+    shr edx, 12 + 9 # last huge page number
     map_p2_table:
     mov [_p2 + ecx * 8], eax
     add eax, 0x200000
     add ecx, 1
     cmp ecx, edx
-    jb map_p2_table
+    jbe map_p2_table
     # p1
     lea eax, __bootloader_start
     and eax, 0xfffff000
@@ -0,0 +1,108 @@
+//! Initial thread local storage (TLS) blocks for the kernel.
+//!
+//! The blocks follow the x86_64 TLS variant II: the thread pointer in the `FS` base register
+//! points to the end of the TLS block, where the thread control block starts. The first word
+//! of the thread control block is a pointer to itself.
+
+use crate::frame_allocator::FrameAllocator;
+use crate::offset_page_table::OffsetPageTable;
+use bootloader::bootinfo::{MemoryRegionType, TlsTemplate};
+use core::{cmp, ptr};
+use usize_conversions::usize_from;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{MapToError, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::{align_up, VirtAddr};
+
+const FS_BASE: u32 = 0xc000_0100;
+
+/// The size of the thread control block, which only contains the self pointer.
+const TCB_SIZE: u64 = 8;
+
+/// Allocates the TLS blocks of the processors in a contiguous virtual memory area.
+///
+/// The block of the `n`th started processor lives at `area_start + n * stride`.
+pub(crate) struct TlsBlocks {
+    template: TlsTemplate,
+    area_start: u64,
+    flags: PageTableFlags,
+    next: u64,
+}
+
+impl TlsBlocks {
+    /// Creates an allocator for TLS blocks that places them at `area_start`.
+    ///
+    /// The start address must be aligned to [`TlsBlocks::area_align`]. The blocks are mapped
+    /// as global pages if `global` is set.
+    pub(crate) fn new(template: TlsTemplate, area_start: u64, global: bool) -> Self {
+        assert!(area_start % Self::area_align(&template) == 0);
+        let mut flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if global {
+            flags |= PageTableFlags::GLOBAL;
+        }
+        TlsBlocks {
+            template,
+            area_start,
+            flags,
+            next: 0,
+        }
+    }
+
+    /// The required alignment of the start address of the area.
+    pub(crate) fn area_align(template: &TlsTemplate) -> u64 {
+        cmp::max(template.align, Size4KiB::SIZE)
+    }
+
+    /// The size of the virtual memory area for the TLS blocks of `count` processors.
+    pub(crate) fn area_size(template: &TlsTemplate, count: u64) -> u64 {
+        Self::stride(template) * count
+    }
+
+    fn block_size(template: &TlsTemplate) -> u64 {
+        align_up(template.mem_size, cmp::max(template.align, 1))
+    }
+
+    fn stride(template: &TlsTemplate) -> u64 {
+        align_up(
+            Self::block_size(template) + TCB_SIZE,
+            Self::area_align(template),
+        )
+    }
+
+    /// Maps and initializes the next TLS block and returns its thread pointer.
+    pub(crate) fn map_next(
+        &mut self,
+        page_table: &mut OffsetPageTable,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<VirtAddr, MapToError> {
+        let block_start = self.area_start + self.next * Self::stride(&self.template);
+        let block_size = Self::block_size(&self.template);
+        let thread_pointer = block_start + block_size;
+
+        let start_page: Page = Page::containing_address(VirtAddr::new(block_start));
+        let end_page = Page::containing_address(VirtAddr::new(thread_pointer + TCB_SIZE - 1));
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = frame_allocator
+                .allocate_frame(MemoryRegionType::Kernel)
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            page_table.map_to(page, frame, self.flags, frame_allocator)?;
+        }
+        self.next += 1;
+
+        // The data part of the template is already mapped as part of a kernel segment.
+        let block = block_start as *mut u8;
+        let file_size = usize_from(self.template.file_size);
+        unsafe {
+            ptr::write_bytes(block, 0, usize_from(block_size));
+            ptr::copy_nonoverlapping(self.template.start_addr as *const u8, block, file_size);
+            (thread_pointer as *mut u64).write(thread_pointer);
+        }
+
+        Ok(VirtAddr::new(thread_pointer))
+    }
+}
+
+/// Sets the `FS` base of the current processor to the passed thread pointer.
+pub(crate) unsafe fn set_thread_pointer(thread_pointer: VirtAddr) {
+    Msr::new(FS_BASE).write(thread_pointer.as_u64());
+}
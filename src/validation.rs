@@ -0,0 +1,162 @@
+//! Checks the kernel executable before anything is mapped, so that invalid kernels result in
+//! a specific error message instead of a failing page table operation.
+
+use crate::segments::Segments;
+use xmas_elf::header::{self, Class, Machine};
+use xmas_elf::program::{ProgramHeader64, Type};
+use xmas_elf::ElfFile;
+
+/// The end of the lower half region that the bootloader uses for itself (page tables, memory
+/// map, stack, code, and the VGA buffer).
+pub(crate) const BOOTLOADER_REGION_END: u64 = 0x20_0000;
+
+/// The start of the higher half of the address space, where all kernel segments must lie.
+const HIGHER_HALF_START: u64 = 0xffff_8000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Panics with a diagnostic if the ELF header of the kernel is not supported.
+pub(crate) fn check_header(elf_file: &ElfFile) {
+    if let Err(err) = header::sanity_check(elf_file) {
+        panic!("invalid kernel executable: {}", err);
+    }
+    let class = elf_file.header.pt1.class();
+    if class != Class::SixtyFour {
+        panic!("kernel executable has class {:?}, expected a 64 bit executable", class);
+    }
+    let machine = elf_file.header.pt2.machine().as_machine();
+    if machine != Machine::X86_64 {
+        panic!("kernel executable has machine type {:?}, expected x86_64", machine);
+    }
+    match elf_file.header.pt2.type_().as_type() {
+        header::Type::Executable | header::Type::SharedObject => {}
+        other => panic!("kernel executable has type {:?}, expected an executable", other),
+    }
+}
+
+/// Panics with a diagnostic if the loadable segments of the kernel are invalid or collide with
+/// the mappings of the bootloader.
+///
+/// The segments must already include the load base, as must the passed entry point. If
+/// `deny_writable_executable` is set, segments that are both writable and executable are
+/// rejected, including segments that would share such a page. Segments must lie in the higher
+/// half of the address space and not in the level 4 entry at `recursive_index`, if passed.
+/// Unless `copy_kernel_segments` is set, the file contents of the segments are mapped in place,
+/// so their file offsets must have the same page offset as their virtual addresses.
+pub(crate) fn check_segments(
+    segments: Segments,
+    entry_point: u64,
+    deny_writable_executable: bool,
+    copy_kernel_segments: bool,
+    recursive_index: Option<u64>,
+) {
+    let mut previous: Option<(usize, ProgramHeader64)> = None;
+    for (index, segment) in segments.iter().enumerate() {
+        if segment.get_type() != Ok(Type::Load) {
+            continue;
+        }
+        let start = segment.virtual_addr;
+        if segment.file_size > segment.mem_size {
+            panic!(
+                "segment {} at {:#x} has a file size ({:#x}) larger than its memory size ({:#x})",
+                index, start, segment.file_size, segment.mem_size
+            );
+        }
+        if !copy_kernel_segments
+            && segment.file_size > 0
+            && start.wrapping_sub(segment.offset) % PAGE_SIZE != 0
+        {
+            panic!(
+                "segment {} at {:#x} has file offset {:#x}, which is not page aligned relative \
+                 to its address, enable `copy_kernel_segments` to load it",
+                index, start, segment.offset
+            );
+        }
+        let end = match start.checked_add(segment.mem_size) {
+            Some(end) if is_canonical(start) && (end == start || is_canonical(end - 1)) => end,
+            _ => panic!(
+                "segment {} at {:#x} with size {:#x} is not in the canonical address space",
+                index, start, segment.mem_size
+            ),
+        };
+        if deny_writable_executable {
+            if segment.flags.is_write() && segment.flags.is_execute() {
+                panic!(
+                    "segment {} at {:#x}..{:#x} is writable and executable",
+                    index, start, end
+                );
+            }
+            // Segments that share a page are mapped with the permissions of both.
+            if let Some((previous_index, ref previous)) = previous {
+                let previous_end = previous.virtual_addr + previous.mem_size;
+                let shared = previous_end > previous.virtual_addr
+                    && (previous_end - 1) / PAGE_SIZE == start / PAGE_SIZE;
+                let writable = previous.flags.is_write() || segment.flags.is_write();
+                let executable = previous.flags.is_execute() || segment.flags.is_execute();
+                if shared && writable && executable {
+                    panic!(
+                        "segments {} and {} share the page at {:#x}, which would be writable \
+                         and executable",
+                        previous_index,
+                        index,
+                        start / PAGE_SIZE * PAGE_SIZE
+                    );
+                }
+            }
+            previous = Some((index, segment));
+        }
+        if start < HIGHER_HALF_START {
+            panic!(
+                "segment {} at {:#x}..{:#x} lies in the lower half of the address space, \
+                 link the kernel above {:#x}",
+                index, start, end, HIGHER_HALF_START
+            );
+        }
+        if let Some(recursive_index) = recursive_index.filter(|_| end > start) {
+            let first = (start >> 39) & 0o777;
+            let last = ((end - 1) >> 39) & 0o777;
+            if first <= recursive_index && recursive_index <= last {
+                panic!(
+                    "segment {} at {:#x}..{:#x} collides with the recursive page table mapping \
+                     (level 4 index {}), use `recursive_index` to move it",
+                    index, start, end, recursive_index
+                );
+            }
+        }
+
+        for (other_index, other) in segments.iter().enumerate().skip(index + 1) {
+            if other.get_type() != Ok(Type::Load) {
+                continue;
+            }
+            let other_end = other.virtual_addr + other.mem_size;
+            if start < other_end && other.virtual_addr < end {
+                panic!(
+                    "segment {} at {:#x}..{:#x} overlaps with segment {} at {:#x}..{:#x}",
+                    index, start, end, other_index, other.virtual_addr, other_end
+                );
+            }
+        }
+    }
+
+    let entry_segment = segments.iter().find(|segment| {
+        segment.get_type() == Ok(Type::Load)
+            && entry_point >= segment.virtual_addr
+            && entry_point < segment.virtual_addr + segment.mem_size
+    });
+    match entry_segment {
+        Some(ref segment) if segment.flags.is_execute() => {}
+        Some(segment) => panic!(
+            "entry point {:#x} lies in segment at {:#x}, which is not executable",
+            entry_point, segment.virtual_addr
+        ),
+        None => panic!(
+            "entry point {:#x} does not lie in a loadable segment",
+            entry_point
+        ),
+    }
+}
+
+fn is_canonical(addr: u64) -> bool {
+    let upper_bits = addr >> 47;
+    upper_bits == 0 || upper_bits == 0x1ffff
+}
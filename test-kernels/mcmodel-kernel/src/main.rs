@@ -0,0 +1,48 @@
+//! A kernel that is linked into the top 2GiB of the address space with `-mcmodel=kernel`.
+//!
+//! It checks that it runs at its link address and that the recursive page table entry was
+//! moved out of the top 512GiB, then exits QEMU with a success code.
+
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::config::Config;
+use bootloader::{bootloader_config, entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+/// The level 4 index of the recursive entry, as entry 511 contains the kernel and entry 510
+/// the VGA buffer mapping of the bootloader.
+const RECURSIVE_INDEX: u64 = 509;
+
+bootloader_config!(Config {
+    recursive_index: RECURSIVE_INDEX,
+    ..Config::DEFAULT
+});
+
+entry_point!(kernel_main);
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    unsafe { exit_qemu(0) };
+    loop {}
+}
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let kernel_main_addr = kernel_main as usize as u64;
+    assert!(kernel_main_addr >= 0xffff_ffff_8000_0000);
+    assert!((boot_info.recursive_page_table_addr >> 39) & 0o777 == RECURSIVE_INDEX);
+
+    // exit QEMU (see https://os.phil-opp.com/integration-tests/#shutting-down-qemu)
+    unsafe { exit_qemu(61) }; // exit code is (61 << 1) | 1 = 123
+
+    loop {}
+}
+
+/// Exits QEMU with the exit code `(value << 1) | 1`.
+pub unsafe fn exit_qemu(value: u32) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::<u32>::new(0xf4);
+    port.write(value);
+}